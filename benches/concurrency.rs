@@ -0,0 +1,86 @@
+// MIT License - free as in freedom; Full license in the LICENSE file
+//! Throughput of the closed model against a local server that answers every request after a fixed delay.
+//! As long as the users do not block each other, the throughput grows with the users far beyond the cores.
+//! Run with `cargo bench --bench concurrency`. The server runs in the same process,
+//! so the largest step needs about 2000 open files (`ulimit -n`).
+use async_std::{
+    io::{ReadExt, WriteExt},
+    net::{TcpListener, TcpStream},
+    task::{block_on, sleep, spawn},
+};
+use garmata::configuration::Configuration;
+use std::{thread::available_parallelism, time::Duration};
+
+const DELAY: Duration = Duration::from_millis(50);
+const DURATION: u64 = 5;
+
+fn main() {
+    let cores = available_parallelism().map_or(1, |cores| cores.get());
+    block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        spawn(async move {
+            loop {
+                if let Ok((stream, _)) = listener.accept().await {
+                    spawn(serve(stream));
+                }
+            }
+        });
+
+        println!("{cores} cores, server delay {DELAY:?}");
+        println!("{:>6} {:>10} {:>12}", "users", "requests", "requests/s");
+        let mut steps = vec![1, cores, 10, 100, 1000];
+        steps.sort();
+        steps.dedup();
+        for users in steps {
+            let config: Configuration = serde_yaml::from_str(&format!(
+                "scheme: http
+target: 127.0.0.1:{port}
+groups:
+  - name: bench
+    users: {users}
+    duration: {DURATION}
+    keep_alive: true
+    flows:
+      - name: delayed
+        path: /
+        method: GET
+"
+            ))
+            .unwrap();
+            let report = garmata::run(config, false, vec![], Default::default())
+                .await
+                .unwrap();
+            let requests = report
+                .summary
+                .flows
+                .iter()
+                .map(|flow| flow.requests - flow.failed())
+                .sum::<usize>();
+            println!(
+                "{users:>6} {requests:>10} {:>12.0}",
+                requests as f64 / report.duration.as_secs_f64()
+            );
+        }
+    });
+}
+
+/// Answers every request of a keep-alive connection after the delay.
+async fn serve(mut stream: TcpStream) {
+    let mut received = vec![];
+    let mut chunk = [0u8; 4096];
+    loop {
+        while let Some(end) = received.windows(4).position(|w| w == b"\r\n\r\n") {
+            received.drain(..end + 4);
+            sleep(DELAY).await;
+            let response = b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok";
+            if stream.write_all(response).await.is_err() {
+                return;
+            }
+        }
+        match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => return,
+            Ok(size) => received.extend_from_slice(&chunk[..size]),
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use crate::{configuration::Checks, http::response::HttpResponse};
+use serde_json::Value;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct CheckResult {
+    pub name: String,
+    pub passed: bool,
+}
+
+/// Evaluates the configured checks against the final response of a flow.
+/// Body checks see the body as received, so compressed bodies only match compressed content.
+pub fn evaluate(
+    checks: &Checks,
+    response: &HttpResponse,
+    total_duration: Duration,
+) -> Vec<CheckResult> {
+    let mut results = vec![];
+    let mut check = |name: String, passed: bool| results.push(CheckResult { name, passed });
+
+    if !checks.status.is_empty() {
+        let status: u16 = response.status.parse().unwrap_or_default();
+        let ranges = checks
+            .status
+            .iter()
+            .map(|range| match range.from == range.to {
+                true => range.from.to_string(),
+                false => format!("{}-{}", range.from, range.to),
+            })
+            .collect::<Vec<String>>()
+            .join(", ");
+        check(
+            format!("status {ranges}"),
+            checks
+                .status
+                .iter()
+                .any(|range| range.from <= status && status <= range.to),
+        );
+    }
+    for header in &checks.headers {
+        let name = header.name.to_lowercase();
+        let value = response.header(&name);
+        if let Some(expected) = &header.equals {
+            check(
+                format!("header {name} equals {expected}"),
+                value == Some(expected.as_str()),
+            );
+        }
+        if let Some(expected) = &header.contains {
+            check(
+                format!("header {name} contains {expected}"),
+                value.is_some_and(|value| value.contains(expected.as_str())),
+            );
+        }
+        if header.equals.is_none() && header.contains.is_none() {
+            check(format!("header {name} present"), value.is_some());
+        }
+    }
+    let body = String::from_utf8_lossy(&response.body);
+    for text in &checks.body_contains {
+        check(
+            format!("body contains {text}"),
+            body.contains(text.as_str()),
+        );
+    }
+    for regex in &checks.body_regex {
+        check(
+            format!("body matches {}", regex.as_str()),
+            regex.is_match(&body),
+        );
+    }
+    if !checks.json.is_empty() {
+        let json: Option<Value> = serde_json::from_slice(&response.body).ok();
+        for json_check in &checks.json {
+            let value = json
+                .as_ref()
+                .and_then(|json| json_path(json, &json_check.path));
+            check(
+                format!("json {} equals {}", json_check.path, json_check.equals),
+                value == Some(&json_check.equals),
+            );
+        }
+    }
+    if let Some(max_latency) = checks.max_latency {
+        check(
+            format!("latency below {max_latency}ms"),
+            total_duration <= Duration::from_millis(max_latency),
+        );
+    }
+    results
+}
+
+/// Resolves a dotted path like `data.items[0].id`, optionally prefixed with `$.`.
+pub fn json_path<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    let path = path.strip_prefix('$').unwrap_or(path);
+    path.replace('[', ".")
+        .replace(']', "")
+        .split('.')
+        .filter(|key| !key.is_empty())
+        .try_fold(value, |value, key| match value {
+            Value::Array(items) => items.get(key.parse::<usize>().ok()?),
+            Value::Object(map) => map.get(key),
+            _ => None,
+        })
+}
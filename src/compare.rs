@@ -0,0 +1,300 @@
+use crate::GarmataError;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Significance level of the tests that tell a regression from noise.
+const SIGNIFICANCE: f64 = 0.05;
+/// Fewer samples make the normal approximation of the tests unreliable, tolerances decide alone.
+const MIN_SAMPLES: f64 = 30.0;
+
+/// The parts of a json summary written with `--output json` that runs are compared by.
+#[derive(Deserialize)]
+pub struct Run {
+    groups: Vec<Group>,
+}
+
+#[derive(Deserialize)]
+struct Group {
+    name: String,
+    flows: Vec<Flow>,
+}
+
+#[derive(Deserialize)]
+struct Flow {
+    name: String,
+    #[serde(default)]
+    stage: String,
+    requests: usize,
+    errors: usize,
+    error_rate: f64,
+    throughput_rps: f64,
+    /// Count, avg, stddev and percentiles in milliseconds, none without any response.
+    latency_ms: Option<HashMap<String, f64>>,
+}
+
+/// A flow of the baseline and the current run, matched by group, flow and stage name.
+pub struct Comparison {
+    pub group: String,
+    pub flow: String,
+    pub stage: String,
+    /// `baseline` or `current` for a flow missing in that run, it has no deltas.
+    pub missing_in: Option<&'static str>,
+    pub deltas: Vec<Delta>,
+}
+
+pub struct Delta {
+    pub metric: String,
+    pub baseline: f64,
+    pub current: f64,
+    pub unit: &'static str,
+    /// Relative change in percent, for the error rate the difference in percentage points.
+    pub change: f64,
+    /// Probability of a difference this large by chance, none if the metric has no test or too few samples.
+    pub p_value: Option<f64>,
+    pub regression: bool,
+}
+
+pub fn load(path: &str) -> Result<Run, GarmataError> {
+    let file = std::fs::File::open(path)
+        .map_err(|e| GarmataError::Configuration(format!("cannot read {path}: {e}")))?;
+    serde_json::from_reader(std::io::BufReader::new(file))
+        .map_err(|e| GarmataError::Configuration(format!("cannot parse {path}: {e}")))
+}
+
+/// The average latency regresses if it grows by more than the tolerance in percent and the Welch's t-test of the means is significant.
+/// Percentiles regress on the tolerance alone, as a tail can grow while the mean stays put.
+/// Throughput regresses if it shrinks by more than the tolerance, the error rate if it grows by more than the error tolerance
+/// in percentage points and a two-proportion z-test is significant.
+pub fn compare(
+    baseline: &Run,
+    current: &Run,
+    tolerance: f64,
+    error_tolerance: f64,
+) -> Vec<Comparison> {
+    let mut comparisons = vec![];
+    for group in &baseline.groups {
+        for flow in &group.flows {
+            let other = find(current, &group.name, flow);
+            comparisons.push(Comparison {
+                group: group.name.clone(),
+                flow: flow.name.clone(),
+                stage: flow.stage.clone(),
+                missing_in: other.is_none().then_some("current"),
+                deltas: other
+                    .map(|other| deltas(flow, other, tolerance, error_tolerance))
+                    .unwrap_or_default(),
+            });
+        }
+    }
+    for group in &current.groups {
+        for flow in &group.flows {
+            if find(baseline, &group.name, flow).is_none() {
+                comparisons.push(Comparison {
+                    group: group.name.clone(),
+                    flow: flow.name.clone(),
+                    stage: flow.stage.clone(),
+                    missing_in: Some("baseline"),
+                    deltas: vec![],
+                });
+            }
+        }
+    }
+    comparisons
+}
+
+fn find<'a>(run: &'a Run, group: &str, flow: &Flow) -> Option<&'a Flow> {
+    run.groups
+        .iter()
+        .filter(|other| other.name == group)
+        .flat_map(|other| &other.flows)
+        .find(|other| other.name == flow.name && other.stage == flow.stage)
+}
+
+fn deltas(baseline: &Flow, current: &Flow, tolerance: f64, error_tolerance: f64) -> Vec<Delta> {
+    let relative = |baseline: f64, current: f64| match baseline == 0.0 {
+        true => 0.0,
+        false => (current - baseline) / baseline * 100.0,
+    };
+    let mut deltas = vec![];
+    if let (Some(before), Some(after)) = (&baseline.latency_ms, &current.latency_ms) {
+        let p_value = welch(before, after);
+        let significant = p_value.is_none_or(|p_value| p_value < SIGNIFICANCE);
+        // Percentiles in the order of their value, only those of both runs.
+        let mut percentiles: Vec<(f64, &String)> = before
+            .keys()
+            .filter(|key| after.contains_key(*key))
+            .filter_map(|key| Some((key.strip_prefix('p')?.parse().ok()?, key)))
+            .collect();
+        percentiles.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let metrics = ["avg"]
+            .into_iter()
+            .chain(percentiles.iter().map(|(_, key)| key.as_str()));
+        for metric in metrics {
+            let (Some(&before), Some(&after)) = (before.get(metric), after.get(metric)) else {
+                continue;
+            };
+            let change = relative(before, after);
+            deltas.push(Delta {
+                metric: metric.to_string(),
+                baseline: before,
+                current: after,
+                unit: "ms",
+                change,
+                p_value: (metric == "avg").then_some(p_value).flatten(),
+                regression: change > tolerance && (metric != "avg" || significant),
+            });
+        }
+    }
+    let change = relative(baseline.throughput_rps, current.throughput_rps);
+    deltas.push(Delta {
+        metric: "throughput".into(),
+        baseline: baseline.throughput_rps,
+        current: current.throughput_rps,
+        unit: "rps",
+        change,
+        p_value: None,
+        regression: change < -tolerance,
+    });
+    let change = current.error_rate - baseline.error_rate;
+    let p_value = proportions(
+        baseline.errors as f64,
+        baseline.requests as f64,
+        current.errors as f64,
+        current.requests as f64,
+    );
+    deltas.push(Delta {
+        metric: "error rate".into(),
+        baseline: baseline.error_rate,
+        current: current.error_rate,
+        unit: "%",
+        change,
+        p_value,
+        regression: change > error_tolerance
+            && p_value.is_none_or(|p_value| p_value < SIGNIFICANCE),
+    });
+    deltas
+}
+
+/// Two-sided p-value of Welch's t-test for the mean latencies, normally approximated.
+fn welch(before: &HashMap<String, f64>, after: &HashMap<String, f64>) -> Option<f64> {
+    let stats = |latencies: &HashMap<String, f64>| {
+        Some((
+            *latencies.get("count")?,
+            *latencies.get("avg")?,
+            *latencies.get("stddev")?,
+        ))
+    };
+    let ((n1, mean1, sd1), (n2, mean2, sd2)) = (stats(before)?, stats(after)?);
+    if n1 < MIN_SAMPLES || n2 < MIN_SAMPLES {
+        return None;
+    }
+    let error = (sd1 * sd1 / n1 + sd2 * sd2 / n2).sqrt();
+    Some(p_value(mean2 - mean1, error))
+}
+
+/// Two-sided p-value of the two-proportion z-test for the error rates.
+fn proportions(errors1: f64, n1: f64, errors2: f64, n2: f64) -> Option<f64> {
+    if n1 < MIN_SAMPLES || n2 < MIN_SAMPLES {
+        return None;
+    }
+    let pooled = (errors1 + errors2) / (n1 + n2);
+    let error = (pooled * (1.0 - pooled) * (1.0 / n1 + 1.0 / n2)).sqrt();
+    Some(p_value(errors2 / n2 - errors1 / n1, error))
+}
+
+/// Equal samples without any spread cannot differ by chance.
+fn p_value(difference: f64, error: f64) -> f64 {
+    match (error == 0.0, difference == 0.0) {
+        (true, true) => 1.0,
+        (true, false) => 0.0,
+        (false, _) => erfc((difference / error).abs() / std::f64::consts::SQRT_2),
+    }
+}
+
+/// Complementary error function for non-negative values, accurate to 1.5e-7 (Abramowitz and Stegun 7.1.26).
+fn erfc(x: f64) -> f64 {
+    let t = 1.0 / (1.0 + 0.3275911 * x);
+    let polynomial = t
+        * (0.254829592
+            + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    polynomial * (-x * x).exp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn latencies(count: f64, avg: f64, stddev: f64) -> HashMap<String, f64> {
+        HashMap::from([
+            ("count".into(), count),
+            ("avg".into(), avg),
+            ("stddev".into(), stddev),
+        ])
+    }
+
+    #[test]
+    fn complementary_error_function() {
+        // Reference values of erfc.
+        for (x, expected) in [
+            (0.0, 1.0),
+            (0.5, 0.4795001),
+            (1.0, 0.1572992),
+            (2.0, 0.0046777),
+        ] {
+            assert!((erfc(x) - expected).abs() < 1e-6, "erfc({x}) = {}", erfc(x));
+        }
+    }
+
+    #[test]
+    fn p_values() {
+        assert_eq!(p_value(0.0, 0.0), 1.0);
+        assert_eq!(p_value(1.0, 0.0), 0.0);
+        // 1.96 standard errors apart is the 5% significance level, in either direction.
+        assert!((p_value(1.96, 1.0) - 0.05).abs() < 1e-3);
+        assert_eq!(p_value(-1.96, 1.0), p_value(1.96, 1.0));
+    }
+
+    #[test]
+    fn welch_test() {
+        let before = latencies(100.0, 10.0, 2.0);
+        assert!(welch(&before, &before).unwrap() > 0.999);
+        assert!(welch(&before, &latencies(100.0, 11.0, 2.0)).unwrap() < SIGNIFICANCE);
+        assert!(welch(&before, &latencies(100.0, 10.3, 2.0)).unwrap() > SIGNIFICANCE);
+        assert_eq!(welch(&before, &latencies(10.0, 20.0, 2.0)), None);
+        assert_eq!(welch(&before, &HashMap::new()), None);
+    }
+
+    #[test]
+    fn proportions_test() {
+        assert_eq!(proportions(0.0, 100.0, 0.0, 100.0), Some(1.0));
+        assert!(proportions(1.0, 1000.0, 30.0, 1000.0).unwrap() < SIGNIFICANCE);
+        assert!(proportions(10.0, 1000.0, 12.0, 1000.0).unwrap() > SIGNIFICANCE);
+        assert_eq!(proportions(0.0, 10.0, 5.0, 10.0), None);
+    }
+
+    fn flow(avg: f64, p99: f64) -> Flow {
+        let mut latency_ms = latencies(1000.0, avg, 2.0);
+        latency_ms.insert("p50".into(), avg);
+        latency_ms.insert("p99".into(), p99);
+        Flow {
+            name: "flow".into(),
+            stage: String::new(),
+            requests: 1000,
+            errors: 0,
+            error_rate: 0.0,
+            throughput_rps: 100.0,
+            latency_ms: Some(latency_ms),
+        }
+    }
+
+    #[test]
+    fn tail_regression_with_an_unchanged_average() {
+        let deltas = deltas(&flow(10.0, 20.0), &flow(10.0, 40.0), 10.0, 1.0);
+        let regressed: Vec<_> = deltas
+            .iter()
+            .filter(|delta| delta.regression)
+            .map(|delta| delta.metric.as_str())
+            .collect();
+        assert_eq!(regressed, ["p99"]);
+    }
+}
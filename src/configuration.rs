@@ -1,5 +1,7 @@
-use serde::Deserialize;
-use std::collections::HashMap;
+use crate::GarmataError;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::{collections::HashMap, fmt::Display};
 
 fn default_scheme() -> String {
     "https".into()
@@ -10,6 +12,32 @@ fn default_http_version() -> String {
 fn default_users() -> usize {
     1
 }
+fn default_percentiles() -> Vec<f64> {
+    vec![50.0, 90.0, 95.0, 99.0]
+}
+fn default_grace_period() -> u64 {
+    10
+}
+fn default_prefix() -> String {
+    "garmata".into()
+}
+fn default_batch_size() -> usize {
+    500
+}
+fn default_buffer() -> usize {
+    10_000
+}
+fn optional_regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Regex>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|regex| Regex::new(&regex).map_err(serde::de::Error::custom))
+        .transpose()
+}
+fn regexes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Regex>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|regex| Regex::new(regex).map_err(serde::de::Error::custom))
+        .collect()
+}
 
 #[derive(Deserialize)]
 pub struct Configuration {
@@ -19,6 +47,205 @@ pub struct Configuration {
     pub http_version: String,
     pub target: String,
     pub groups: Vec<Group>,
+    #[serde(default)]
+    pub thresholds: Vec<Threshold>,
+    /// Latency percentiles printed in the stats summary.
+    #[serde(default = "default_percentiles")]
+    pub percentiles: Vec<f64>,
+    /// Seconds per entry of the time series of every flow, none to collect no time series.
+    #[serde(default)]
+    pub interval: Option<u64>,
+    /// Seconds iterations in flight may take to finish after an interrupt.
+    #[serde(default = "default_grace_period")]
+    pub grace_period: u64,
+    #[serde(default)]
+    pub data: Option<Data>,
+    /// Services the results are pushed to while the test runs.
+    #[serde(default)]
+    pub sinks: Vec<Sink>,
+    #[serde(flatten)]
+    pub timeouts: Timeouts,
+}
+
+/// Timeouts in milliseconds. A flow inherits unset timeouts from its group and the configuration.
+#[derive(Deserialize, Clone, Copy, Default)]
+pub struct Timeouts {
+    /// Limit to establish the TCP connection.
+    #[serde(default)]
+    pub connect_timeout: Option<u64>,
+    /// Limit for every single read from the connection.
+    #[serde(default)]
+    pub read_timeout: Option<u64>,
+    /// Limit for the whole flow including the connection setup and redirects.
+    #[serde(default)]
+    pub request_timeout: Option<u64>,
+}
+
+impl Timeouts {
+    pub fn or(self, other: Timeouts) -> Timeouts {
+        Timeouts {
+            connect_timeout: self.connect_timeout.or(other.connect_timeout),
+            read_timeout: self.read_timeout.or(other.read_timeout),
+            request_timeout: self.request_timeout.or(other.request_timeout),
+        }
+    }
+}
+
+/// A service the results are pushed to, exactly one of the influx_http, influx_udp and statsd targets is set.
+#[derive(Deserialize, Clone)]
+pub struct Sink {
+    /// Write url of InfluxDB for line protocol over HTTP.
+    #[serde(default)]
+    pub influx_http: Option<String>,
+    /// host:port of a UDP listener for line protocol, like the one of Telegraf.
+    #[serde(default)]
+    pub influx_udp: Option<String>,
+    /// host:port of a StatsD server.
+    #[serde(default)]
+    pub statsd: Option<String>,
+    /// Sent as `Authorization: Token <token>` with influx_http.
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Measurement name of the line protocol or first part of the StatsD metric names.
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    /// Push one aggregate per flow and second instead of every result.
+    #[serde(default)]
+    pub aggregate: bool,
+    /// Results sent at once.
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// Results waiting to be sent. Results that do not fit are dropped instead of slowing down the test.
+    #[serde(default = "default_buffer")]
+    pub buffer: usize,
+}
+
+/// A csv or json lines file whose rows are handed to the virtual users as variables.
+#[derive(Deserialize, Clone)]
+pub struct Data {
+    /// Relative paths are resolved from the directory of the configuration file.
+    pub file: String,
+    #[serde(default)]
+    pub order: DataOrder,
+    /// Defaults to `stop_user` for unique rows, so users never share a row, and to `recycle` otherwise.
+    #[serde(default)]
+    pub on_exhausted: Option<Exhausted>,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DataOrder {
+    /// Every iteration of any user takes the next row.
+    #[default]
+    Sequential,
+    /// Every iteration takes a random row. The rows never run out.
+    Random,
+    /// Every user takes the next row once and keeps it for all its iterations.
+    Unique,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Exhausted {
+    /// Start again with the first row.
+    Recycle,
+    /// Users without a row stop, the others continue.
+    StopUser,
+    /// The first user without a row stops all groups.
+    StopTest,
+}
+
+impl Configuration {
+    pub fn load(path: &str) -> Result<Self, GarmataError> {
+        match std::fs::File::open(path) {
+            Ok(file) => match serde_yaml::from_reader::<_, Self>(file) {
+                Ok(mut config) => {
+                    if let Some(data) = config.data.as_mut() {
+                        if let Some(directory) = std::path::Path::new(path).parent() {
+                            data.file = directory.join(&data.file).to_string_lossy().to_string();
+                        }
+                    }
+                    let extracts = config
+                        .groups
+                        .iter()
+                        .flat_map(|g| &g.flows)
+                        .flat_map(|f| &f.extract);
+                    for extract in extracts {
+                        let sources = [
+                            extract.regex.is_some(),
+                            extract.json.is_some(),
+                            extract.header.is_some(),
+                            extract.cookie.is_some(),
+                        ];
+                        if sources.iter().filter(|&&source| source).count() != 1 {
+                            return Err(GarmataError::Configuration(format!(
+                                "extract {} needs exactly one of regex, json, header or cookie",
+                                extract.name
+                            )));
+                        }
+                    }
+                    let timeouts = config
+                        .groups
+                        .iter()
+                        .flat_map(|g| g.flows.iter().map(|f| &f.timeouts).chain([&g.timeouts]))
+                        .chain([&config.timeouts]);
+                    for timeouts in timeouts {
+                        let Timeouts {
+                            connect_timeout,
+                            read_timeout,
+                            request_timeout,
+                        } = timeouts;
+                        if [connect_timeout, read_timeout, request_timeout].contains(&&Some(0)) {
+                            return Err(GarmataError::Configuration(
+                                "timeouts must be greater than 0 milliseconds".into(),
+                            ));
+                        }
+                    }
+                    if config
+                        .percentiles
+                        .iter()
+                        .any(|&percentile| !(percentile > 0.0 && percentile <= 100.0))
+                    {
+                        return Err(GarmataError::Configuration(
+                            "percentiles must be greater than 0 and at most 100".into(),
+                        ));
+                    }
+                    if config.interval == Some(0) {
+                        return Err(GarmataError::Configuration(
+                            "interval must be greater than 0 seconds".into(),
+                        ));
+                    }
+                    for sink in &config.sinks {
+                        let targets = [&sink.influx_http, &sink.influx_udp, &sink.statsd];
+                        if targets.iter().filter(|target| target.is_some()).count() != 1 {
+                            return Err(GarmataError::Configuration(
+                                "sink needs exactly one of influx_http, influx_udp or statsd"
+                                    .into(),
+                            ));
+                        }
+                        if sink.batch_size == 0 || sink.buffer == 0 {
+                            return Err(GarmataError::Configuration(
+                                "sink batch_size and buffer must be greater than 0".into(),
+                            ));
+                        }
+                    }
+                    for threshold in &config.thresholds {
+                        if threshold.below.is_none() && threshold.above.is_none() {
+                            return Err(GarmataError::Configuration(format!(
+                                "threshold {} needs a below or above value",
+                                threshold.metric
+                            )));
+                        }
+                    }
+                    Ok(config)
+                }
+                Err(e) => Err(GarmataError::Configuration(format!(
+                    "cannot parse {path}: {e}"
+                ))),
+            },
+            Err(e) => Err(GarmataError::Configuration(e.to_string())),
+        }
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -37,6 +264,109 @@ pub struct Flow {
     pub insecure: bool,
     #[serde(default)]
     pub cookies: Vec<String>,
+    #[serde(default)]
+    pub keep_alive: Option<bool>,
+    #[serde(flatten)]
+    pub timeouts: Timeouts,
+    #[serde(default)]
+    pub checks: Checks,
+    #[serde(default)]
+    pub extract: Vec<Extract>,
+}
+
+/// Stores a value of the final response of a flow as variable of the virtual user.
+/// Exactly one source of regex, json, header or cookie has to be set.
+#[derive(Deserialize, Clone)]
+pub struct Extract {
+    pub name: String,
+    /// The first capture group or the whole match if there is none.
+    #[serde(default, deserialize_with = "optional_regex")]
+    pub regex: Option<Regex>,
+    /// Dotted path into the response body like `data.items[0].id`.
+    #[serde(default)]
+    pub json: Option<String>,
+    #[serde(default)]
+    pub header: Option<String>,
+    #[serde(default)]
+    pub cookie: Option<String>,
+}
+
+/// Assertions on the final response of a flow. Each entry is counted as a separate check.
+#[derive(Deserialize, Clone, Default)]
+pub struct Checks {
+    #[serde(default)]
+    pub status: Vec<StatusRange>,
+    #[serde(default)]
+    pub headers: Vec<HeaderCheck>,
+    #[serde(default)]
+    pub body_contains: Vec<String>,
+    #[serde(default, deserialize_with = "regexes")]
+    pub body_regex: Vec<Regex>,
+    #[serde(default)]
+    pub json: Vec<JsonCheck>,
+    /// Upper bound of the total duration in milliseconds.
+    #[serde(default)]
+    pub max_latency: Option<u64>,
+}
+
+/// Status codes accepted as `200`, `"200-299"` or `"2xx"`.
+#[derive(Deserialize, Clone)]
+#[serde(try_from = "StatusPattern")]
+pub struct StatusRange {
+    pub from: u16,
+    pub to: u16,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StatusPattern {
+    Code(u16),
+    Pattern(String),
+}
+
+impl TryFrom<StatusPattern> for StatusRange {
+    type Error = String;
+
+    fn try_from(value: StatusPattern) -> Result<Self, Self::Error> {
+        let pattern = match value {
+            StatusPattern::Code(code) => {
+                return Ok(Self {
+                    from: code,
+                    to: code,
+                })
+            }
+            StatusPattern::Pattern(pattern) => pattern,
+        };
+        let invalid = || format!("invalid status pattern {pattern}");
+        let (from, to) = match pattern.split_once('-') {
+            Some((from, to)) => (from.trim().to_string(), to.trim().to_string()),
+            None if pattern.to_lowercase().ends_with("xx") => (
+                pattern.to_lowercase().replace('x', "0"),
+                pattern.to_lowercase().replace('x', "9"),
+            ),
+            None => (pattern.clone(), pattern.clone()),
+        };
+        match (from.parse(), to.parse()) {
+            (Ok(from), Ok(to)) if from <= to => Ok(Self { from, to }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct HeaderCheck {
+    pub name: String,
+    #[serde(default)]
+    pub equals: Option<String>,
+    #[serde(default)]
+    pub contains: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct JsonCheck {
+    /// Dotted path into the response body like `data.items[0].id`.
+    pub path: String,
+    pub equals: serde_json::Value,
 }
 
 #[derive(Deserialize, Clone)]
@@ -47,5 +377,133 @@ pub struct Group {
     pub users: usize,
     #[serde(default)]
     pub duration: u64,
+    #[serde(default)]
+    pub rate: Option<f64>,
+    #[serde(default)]
+    pub stages: Vec<Stage>,
+    #[serde(default)]
+    pub keep_alive: Option<bool>,
+    #[serde(flatten)]
+    pub timeouts: Timeouts,
+    #[serde(default)]
+    pub cookie_jar: CookieJarScope,
     pub flows: Vec<Flow>,
 }
+
+/// Lifetime of the cookies a virtual user received.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CookieJarScope {
+    /// Cookies are kept for the whole run of the user.
+    #[default]
+    User,
+    /// Every iteration of the flows starts with an empty cookie jar.
+    Iteration,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Stage {
+    #[serde(default)]
+    pub name: String,
+    pub duration: u64,
+    pub users: usize,
+}
+
+/// A limit for a metric of the whole run, a group or a flow. Breaching it fails the run.
+#[derive(Deserialize, Clone)]
+pub struct Threshold {
+    pub metric: Metric,
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    pub flow: Option<String>,
+    #[serde(default)]
+    pub below: Option<f64>,
+    #[serde(default)]
+    pub above: Option<f64>,
+}
+
+/// Latencies are compared in milliseconds, rates in percent and throughput in requests per second.
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub enum Metric {
+    Min,
+    Avg,
+    Max,
+    Percentile(f64),
+    ErrorRate,
+    CheckFailureRate,
+    Throughput,
+}
+
+impl TryFrom<String> for Metric {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "min" => Ok(Metric::Min),
+            "avg" => Ok(Metric::Avg),
+            "max" => Ok(Metric::Max),
+            "error_rate" => Ok(Metric::ErrorRate),
+            "check_failure_rate" => Ok(Metric::CheckFailureRate),
+            "throughput" => Ok(Metric::Throughput),
+            _ => match value.strip_prefix('p').map(str::parse::<f64>) {
+                Some(Ok(percentile)) if percentile > 0.0 && percentile <= 100.0 => {
+                    Ok(Metric::Percentile(percentile))
+                }
+                _ => Err(format!("unknown threshold metric {value}")),
+            },
+        }
+    }
+}
+
+impl Display for Metric {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Metric::Min => write!(f, "min"),
+            Metric::Avg => write!(f, "avg"),
+            Metric::Max => write!(f, "max"),
+            Metric::Percentile(percentile) => write!(f, "p{percentile}"),
+            Metric::ErrorRate => write!(f, "error_rate"),
+            Metric::CheckFailureRate => write!(f, "check_failure_rate"),
+            Metric::Throughput => write!(f, "throughput"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(pattern: StatusPattern) -> Result<(u16, u16), String> {
+        StatusRange::try_from(pattern).map(|range| (range.from, range.to))
+    }
+
+    #[test]
+    fn status_ranges() {
+        let pattern = |pattern: &str| range(StatusPattern::Pattern(pattern.into()));
+        assert_eq!(range(StatusPattern::Code(204)), Ok((204, 204)));
+        assert_eq!(pattern("201"), Ok((201, 201)));
+        assert_eq!(pattern("200 - 299"), Ok((200, 299)));
+        assert_eq!(pattern("2xx"), Ok((200, 299)));
+        assert_eq!(pattern("4XX"), Ok((400, 499)));
+    }
+
+    #[test]
+    fn invalid_status_ranges() {
+        for invalid in ["299-200", "abc", "2x", "200-", ""] {
+            assert_eq!(
+                range(StatusPattern::Pattern(invalid.into())),
+                Err(format!("invalid status pattern {invalid}"))
+            );
+        }
+    }
+
+    #[test]
+    fn status_ranges_from_yaml() {
+        let checks: Checks = serde_yaml::from_str("status: [200, \"3xx\"]").unwrap();
+        let ranges: Vec<_> = checks.status.iter().map(|r| (r.from, r.to)).collect();
+        assert_eq!(ranges, [(200, 200), (300, 399)]);
+        assert!(serde_yaml::from_str::<Checks>("status: [\"5yy\"]").is_err());
+    }
+}
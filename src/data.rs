@@ -0,0 +1,88 @@
+use crate::{
+    configuration::{Data, DataOrder, Exhausted},
+    GarmataError,
+};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Rows of a data file handed to the virtual users as variables.
+pub struct Feeder {
+    rows: Vec<HashMap<String, String>>,
+    order: DataOrder,
+    pub on_exhausted: Exhausted,
+    cursor: AtomicUsize,
+}
+
+impl Feeder {
+    /// Loads a csv file with a header line or a json lines file with one object per line.
+    pub fn load(data: &Data) -> Result<Self, GarmataError> {
+        let error = |e: &dyn std::fmt::Display| {
+            GarmataError::Configuration(format!("cannot read data file {}: {e}", data.file))
+        };
+        let mut rows = vec![];
+        if data.file.ends_with(".csv") {
+            let mut reader = csv::Reader::from_path(&data.file).map_err(|e| error(&e))?;
+            let headers = reader.headers().map_err(|e| error(&e))?.clone();
+            for record in reader.records() {
+                let record = record.map_err(|e| error(&e))?;
+                rows.push(
+                    headers
+                        .iter()
+                        .zip(record.iter())
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect(),
+                );
+            }
+        } else {
+            let content = std::fs::read_to_string(&data.file).map_err(|e| error(&e))?;
+            for line in content.lines().filter(|line| !line.trim().is_empty()) {
+                let row: HashMap<String, Value> =
+                    serde_json::from_str(line).map_err(|e| error(&e))?;
+                rows.push(
+                    row.into_iter()
+                        .map(|(k, v)| match v {
+                            Value::String(v) => (k, v),
+                            v => (k, v.to_string()),
+                        })
+                        .collect(),
+                );
+            }
+        }
+        if rows.is_empty() {
+            return Err(error(&"no rows found"));
+        }
+        Ok(Self {
+            rows,
+            order: data.order,
+            on_exhausted: data.on_exhausted.unwrap_or(match data.order {
+                DataOrder::Unique => Exhausted::StopUser,
+                DataOrder::Sequential | DataOrder::Random => Exhausted::Recycle,
+            }),
+            cursor: AtomicUsize::new(0),
+        })
+    }
+
+    /// Row index for the next iteration of a user that currently holds the given row.
+    /// Returns none once the rows ran out and are not recycled.
+    pub fn next(&self, current: Option<usize>) -> Option<usize> {
+        match (self.order, current) {
+            (DataOrder::Random, _) => Some(fastrand::usize(..self.rows.len())),
+            (DataOrder::Unique, Some(current)) => Some(current),
+            (DataOrder::Sequential | DataOrder::Unique, _) => {
+                let index = self.cursor.fetch_add(1, Ordering::SeqCst);
+                match self.on_exhausted {
+                    Exhausted::Recycle => Some(index % self.rows.len()),
+                    _ if index < self.rows.len() => Some(index),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    pub fn row(&self, index: usize) -> &HashMap<String, String> {
+        &self.rows[index]
+    }
+}
@@ -0,0 +1,205 @@
+use std::{error::Error, fmt, io};
+use url::Url;
+
+/// Errors of loading a configuration and of the requests of a run.
+/// Request errors carry the url and end up in the results instead of stopping the run.
+#[derive(Debug)]
+pub enum GarmataError {
+    /// The configuration or one of the files it references cannot be used.
+    Configuration(String),
+    /// The host of the url could not be resolved.
+    Dns {
+        url: Url,
+        source: Box<dyn Error + Send + Sync>,
+    },
+    /// No TCP connection could be established.
+    Connect { url: Url, source: io::Error },
+    /// The TLS handshake failed, for example because of an invalid certificate.
+    Tls {
+        url: Url,
+        source: Box<dyn Error + Send + Sync>,
+    },
+    /// Sending or receiving failed or the server closed the connection early.
+    Io {
+        url: Url,
+        phase: Phase,
+        source: io::Error,
+    },
+    /// The server sent something that is not valid HTTP.
+    Protocol {
+        url: Url,
+        phase: Phase,
+        reason: String,
+    },
+    /// A redirect response cannot be followed.
+    Redirect { url: Url, reason: String },
+    /// A connect, read or request timeout elapsed.
+    Timeout { url: Url, phase: Phase },
+}
+
+impl GarmataError {
+    /// Category of a failed request, none for configuration errors.
+    pub fn category(&self) -> Option<ErrorCategory> {
+        match self {
+            Self::Configuration(_) => None,
+            Self::Dns { .. } => Some(ErrorCategory::Dns),
+            Self::Connect { .. } => Some(ErrorCategory::Connect),
+            Self::Tls { .. } => Some(ErrorCategory::Tls),
+            Self::Io { phase, .. } if *phase <= Phase::Sending => Some(ErrorCategory::Send),
+            Self::Io { .. } => Some(ErrorCategory::Read),
+            Self::Protocol { .. } | Self::Redirect { .. } => Some(ErrorCategory::Parse),
+            Self::Timeout { .. } => Some(ErrorCategory::Timeout),
+        }
+    }
+
+    /// Phase of the request the error happened in, none for configuration errors.
+    pub fn phase(&self) -> Option<Phase> {
+        match self {
+            Self::Configuration(_) => None,
+            Self::Dns { .. } => Some(Phase::Dns),
+            Self::Connect { .. } => Some(Phase::Connect),
+            Self::Tls { .. } => Some(Phase::Tls),
+            Self::Io { phase, .. } | Self::Protocol { phase, .. } | Self::Timeout { phase, .. } => {
+                Some(*phase)
+            }
+            Self::Redirect { .. } => Some(Phase::Downloading),
+        }
+    }
+
+    /// I/O error of a request. Reads and writes beyond their timeout fail as timeouts.
+    pub(crate) fn io(url: &Url, phase: Phase, source: io::Error) -> Self {
+        match source.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Self::Timeout {
+                url: url.clone(),
+                phase,
+            },
+            _ => Self::Io {
+                url: url.clone(),
+                phase,
+                source,
+            },
+        }
+    }
+
+    /// The server closed the connection before any byte of the response arrived,
+    /// as it happens to keep-alive connections the server closed while they were idle.
+    pub(crate) fn is_closed_before_response(&self) -> bool {
+        match self {
+            Self::Io {
+                phase: Phase::Sending | Phase::Waiting,
+                source,
+                ..
+            } => matches!(
+                source.kind(),
+                io::ErrorKind::UnexpectedEof
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+            ),
+            _ => false,
+        }
+    }
+
+    /// Moves an I/O, protocol or timeout error to the phase the caller was in.
+    pub(crate) fn during(mut self, new_phase: Phase) -> Self {
+        if let Self::Io { phase, .. } | Self::Protocol { phase, .. } | Self::Timeout { phase, .. } =
+            &mut self
+        {
+            *phase = new_phase;
+        }
+        self
+    }
+}
+
+impl fmt::Display for GarmataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Configuration(reason) => f.write_str(reason),
+            Self::Dns { url, source } => write!(f, "cannot resolve the host of {url}: {source}"),
+            Self::Connect { url, source } => write!(f, "cannot connect to {url}: {source}"),
+            Self::Tls { url, source } => {
+                write!(f, "cannot establish a tls handshake to {url}: {source}")
+            }
+            Self::Io { url, phase, source } => {
+                write!(f, "I/O error while {phase} for {url}: {source}")
+            }
+            Self::Protocol { url, phase, reason } => {
+                write!(f, "invalid response while {phase} for {url}: {reason}")
+            }
+            Self::Redirect { url, reason } => {
+                write!(f, "cannot follow the redirect of {url}: {reason}")
+            }
+            Self::Timeout { url, phase } => write!(f, "timeout while {phase} for {url}"),
+        }
+    }
+}
+
+impl Error for GarmataError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Dns { source, .. } | Self::Tls { source, .. } => Some(source.as_ref()),
+            Self::Connect { source, .. } | Self::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCategory {
+    Dns,
+    Connect,
+    Tls,
+    Send,
+    Read,
+    Parse,
+    Timeout,
+}
+impl fmt::Display for ErrorCategory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Dns => "dns",
+            Self::Connect => "connect",
+            Self::Tls => "tls",
+            Self::Send => "send",
+            Self::Read => "read",
+            Self::Parse => "parse",
+            Self::Timeout => "timeout",
+        })
+    }
+}
+
+/// Phases of a request in the order they happen, named like the durations of a result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    Dns,
+    Connect,
+    Tls,
+    Sending,
+    Waiting,
+    Downloading,
+}
+impl Phase {
+    /// Short name of the phase in metrics and in the json summary.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Dns => "dns",
+            Self::Connect => "connect",
+            Self::Tls => "tls",
+            Self::Sending => "sending",
+            Self::Waiting => "waiting",
+            Self::Downloading => "downloading",
+        }
+    }
+}
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Dns => "dns lookup",
+            Self::Connect => "connecting",
+            Self::Tls => "tls handshake",
+            Self::Sending => "sending",
+            Self::Waiting => "waiting",
+            Self::Downloading => "downloading",
+        })
+    }
+}
@@ -0,0 +1,51 @@
+use crate::{checks::json_path, configuration::Extract, http::response::HttpResponse};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Stores the extracted values in the variables. Variables without a match keep their previous value.
+pub fn apply(
+    extracts: &[Extract],
+    response: &HttpResponse,
+    variables: &mut HashMap<String, String>,
+) {
+    if extracts.is_empty() {
+        return;
+    }
+    let body = String::from_utf8_lossy(&response.body);
+    let json: Option<Value> = match extracts.iter().any(|extract| extract.json.is_some()) {
+        true => serde_json::from_slice(&response.body).ok(),
+        false => None,
+    };
+    for extract in extracts {
+        let value = if let Some(regex) = &extract.regex {
+            regex.captures(&body).and_then(|captures| {
+                captures
+                    .get(1)
+                    .or_else(|| captures.get(0))
+                    .map(|m| m.as_str().to_string())
+            })
+        } else if let Some(path) = &extract.json {
+            json.as_ref()
+                .and_then(|json| json_path(json, path))
+                .map(|value| match value {
+                    Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                })
+        } else if let Some(header) = &extract.header {
+            response.header(&header.to_lowercase()).map(str::to_string)
+        } else if let Some(cookie) = &extract.cookie {
+            response
+                .headers
+                .iter()
+                .filter(|(k, _)| k == "set-cookie")
+                .filter_map(|(_, v)| v.split(';').next()?.split_once('='))
+                .find(|(name, _)| name.trim() == cookie)
+                .map(|(_, value)| value.trim().to_string())
+        } else {
+            None
+        };
+        if let Some(value) = value {
+            variables.insert(extract.name.clone(), value);
+        }
+    }
+}
@@ -1,9 +1,12 @@
-use std::time::Duration;
+use crate::{checks::CheckResult, error::Phase, GarmataError};
+use std::{sync::Arc, time::Duration};
 pub mod cookies;
+pub(crate) mod http2;
 pub mod response;
 pub mod request;
+pub(crate) mod tls;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct HttpResult {
     pub group: String,
     pub flow: String,
@@ -17,4 +20,41 @@ pub struct HttpResult {
     pub download_duration: Duration,
     pub total_duration: Duration,
     pub response_status: String,
-}
\ No newline at end of file
+    pub stage: String,
+    pub connection_reused: bool,
+    pub received_bytes: usize,
+    pub checks: Vec<CheckResult>,
+    pub check_failed: bool,
+    /// Why no complete response was received. The durations cover the phases until the failure.
+    pub error: Option<Arc<GarmataError>>,
+}
+impl HttpResult {
+    /// Failed requests and responses with a status other than 1xx, 2xx or 3xx.
+    pub fn is_error(&self) -> bool {
+        self.error.is_some() || !matches!(self.response_status.parse::<u16>(), Ok(100..=399))
+    }
+
+    /// Error category of a failed request or `status` for a response with an error status, none without an error.
+    pub fn error_kind(&self) -> Option<String> {
+        if !self.is_error() {
+            return None;
+        }
+        match self.error.as_ref().and_then(|error| error.category()) {
+            Some(category) => Some(category.to_string()),
+            None => Some("status".into()),
+        }
+    }
+
+    /// Durations of the phases of the final request.
+    /// Failed requests count as errors but have no latency, so only results without an error should be measured.
+    pub fn phase_durations(&self) -> [(Phase, Duration); 6] {
+        [
+            (Phase::Dns, self.dns_duration),
+            (Phase::Connect, self.connect_duration),
+            (Phase::Tls, self.tls_duration),
+            (Phase::Sending, self.sending_duration),
+            (Phase::Waiting, self.waiting_duration),
+            (Phase::Downloading, self.download_duration),
+        ]
+    }
+}
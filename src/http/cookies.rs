@@ -19,9 +19,9 @@ impl Cookiejar {
         for cookie in &self.cookies {
             // Is a more strict implementation required?
             let valid_domain = if cookie.including_subdomains {
-                host == cookie.domain
+                host == cookie.domain || host.ends_with(&format!(".{}", cookie.domain))
             } else {
-                host.ends_with(&cookie.domain)
+                host == cookie.domain
             };
             if valid_domain && path.starts_with(&cookie.path) {
                 scoped_cookies.push(cookie);
@@ -33,7 +33,7 @@ impl Cookiejar {
     pub fn set_all(&mut self, cookies: &Vec<String>, current_host: String) {
         for cookie in cookies {
             let cookie: Vec<&str> = cookie.split("; ").collect();
-            let key_val: Vec<&str> = cookie[0].split('=').collect();
+            let key_val: Vec<&str> = cookie[0].splitn(2, '=').collect();
 
             // https://datatracker.ietf.org/doc/html/rfc6265#section-4.1.2.3
             let (domain, including_subdomains) = match cookie
@@ -44,9 +44,9 @@ impl Cookiejar {
                 Some(cookie_value) => {
                     // https://datatracker.ietf.org/doc/html/rfc6265#section-5.2.3
                     let mut domain = cookie_value
-                        .split('=')
-                        .nth(1)
-                        .unwrap_or_else(||panic!("invalid Domain cookie value for {cookie_value}"))
+                        .split_once('=')
+                        .map(|(_, value)| value)
+                        .unwrap_or_default()
                         .to_string();
                     if domain.starts_with('.') {
                         domain = domain.chars().skip(1).collect()
@@ -63,13 +63,13 @@ impl Cookiejar {
             let path = match cookie
                 .iter()
                 .skip(1)
-                .find(|&c| c.to_lowercase().starts_with("Path="))
+                .find(|&c| c.to_lowercase().starts_with("path="))
             {
                 Some(cookie_value) => {
                     let mut path = cookie_value
-                        .split('=')
-                        .nth(1)
-                        .unwrap_or_else(|| panic!("invalid Path cookie value for {cookie_value}"))
+                        .split_once('=')
+                        .map(|(_, value)| value)
+                        .unwrap_or_default()
                         .to_string();
                     if path.ends_with('/') {
                         let mut chars = path.chars();
@@ -81,15 +81,33 @@ impl Cookiejar {
                 None => "/".to_string(),
             };
 
+            // https://datatracker.ietf.org/doc/html/rfc6265#section-5.2.2
+            let expired = cookie.iter().skip(1).any(|&c| {
+                let attribute = c.to_lowercase();
+                if let Some(max_age) = attribute.strip_prefix("max-age=") {
+                    max_age.parse::<i64>().is_ok_and(|max_age| max_age <= 0)
+                } else if attribute.starts_with("expires=") {
+                    chrono::DateTime::parse_from_rfc2822(&c[8..])
+                        .is_ok_and(|expires| expires < chrono::Utc::now())
+                } else {
+                    false
+                }
+            });
+
             // Is a more strict implementation required?
-            if domain.ends_with(&current_host) {
+            if domain.ends_with(&current_host) || current_host.ends_with(&domain) {
                 let name = key_val[0].to_string();
-                let value = key_val[1].to_string();
+                let value = key_val.get(1).unwrap_or(&"").to_string();
                 let position = self
                     .cookies
                     .iter()
                     .position(|c| c.domain == domain && c.name == name);
-                if let Some(index) = position {
+                if expired {
+                    // Servers delete cookies by sending them already expired.
+                    if let Some(index) = position {
+                        self.cookies.remove(index);
+                    }
+                } else if let Some(index) = position {
                     self.cookies[index] = Cookie {
                         including_subdomains,
                         domain,
@@ -0,0 +1,527 @@
+use std::time::{Duration, Instant};
+
+use async_std::io::{self, ReadExt, WriteExt};
+use url::Url;
+
+use super::{cookies::Cookie, request::HttpRequest, response::HttpResponse};
+use crate::{
+    error::{GarmataError, Phase},
+    ReadAndWrite,
+};
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY: u8 = 0x20;
+
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+/// Flow control window announced to the server, large enough to never stall a download.
+const RECEIVE_WINDOW: u32 = 1 << 24;
+const DEFAULT_WINDOW: i64 = 65_535;
+
+/// HTTP/2 connection of one virtual user. The requests of the user are sent as consecutive streams on it.
+pub(crate) struct Http2Connection {
+    stream: Box<dyn ReadAndWrite>,
+    /// Url of the current request for errors.
+    url: Url,
+    encoder: hpack::Encoder<'static>,
+    decoder: hpack::Decoder<'static>,
+    next_stream_id: u32,
+    send_window: i64,
+    initial_send_window: i64,
+    max_frame_size: usize,
+    received_bytes: usize,
+    /// The server announced the shutdown of the connection with a GOAWAY frame.
+    pub closed: bool,
+}
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: Vec<u8>,
+}
+
+impl Http2Connection {
+    /// Sends the connection preface. The server settings are applied as they arrive with the first response.
+    pub async fn handshake(stream: Box<dyn ReadAndWrite>, url: &Url) -> Result<Self, GarmataError> {
+        let mut settings = vec![];
+        for (id, value) in [
+            (SETTINGS_ENABLE_PUSH, 0),
+            (SETTINGS_INITIAL_WINDOW_SIZE, RECEIVE_WINDOW),
+        ] {
+            settings.extend_from_slice(&id.to_be_bytes());
+            settings.extend_from_slice(&value.to_be_bytes());
+        }
+        let mut payload = PREFACE.to_vec();
+        payload.extend(frame_bytes(SETTINGS, 0, 0, &settings));
+        payload.extend(frame_bytes(
+            WINDOW_UPDATE,
+            0,
+            0,
+            &(RECEIVE_WINDOW - DEFAULT_WINDOW as u32).to_be_bytes(),
+        ));
+        let mut connection = Self {
+            stream,
+            url: url.clone(),
+            encoder: hpack::Encoder::new(),
+            decoder: hpack::Decoder::new(),
+            next_stream_id: 1,
+            send_window: DEFAULT_WINDOW,
+            initial_send_window: DEFAULT_WINDOW,
+            max_frame_size: 16_384,
+            received_bytes: 0,
+            closed: false,
+        };
+        connection
+            .write(&payload)
+            .await
+            .map_err(|e| e.during(Phase::Connect))?;
+        Ok(connection)
+    }
+
+    pub async fn request(
+        &mut self,
+        http_request: &HttpRequest,
+        cookies: Vec<&Cookie>,
+        is_debug: bool,
+    ) -> Result<(Duration, Duration, Duration, HttpResponse), GarmataError> {
+        let stream_id = self.next_stream_id;
+        self.next_stream_id += 2;
+        self.url = http_request.url.clone();
+        let headers = http_request.http2_headers(cookies);
+        if is_debug {
+            for (k, v) in &headers {
+                println!("{k}: {v}");
+            }
+            println!("\n{}", http_request.body);
+        }
+        let block = self
+            .encoder
+            .encode(headers.iter().map(|(k, v)| (k.as_bytes(), v.as_bytes())));
+        let body = http_request.body.as_bytes();
+
+        let start = Instant::now();
+        let fragments: Vec<&[u8]> = block.chunks(self.max_frame_size).collect();
+        let mut payload = vec![];
+        for (index, fragment) in fragments.iter().enumerate() {
+            let kind = if index == 0 { HEADERS } else { CONTINUATION };
+            let mut flags = 0;
+            if index == 0 && body.is_empty() {
+                flags |= END_STREAM;
+            }
+            if index == fragments.len() - 1 {
+                flags |= END_HEADERS;
+            }
+            payload.extend(frame_bytes(kind, flags, stream_id, fragment));
+        }
+        self.write(&payload).await?;
+        self.send_body(stream_id, body)
+            .await
+            .map_err(|e| e.during(Phase::Sending))?;
+        let sending_duration = start.elapsed();
+
+        let mut start = Instant::now();
+        let mut waiting_duration = None;
+        self.received_bytes = 0;
+        let mut response: Option<HttpResponse> = None;
+        let mut header_block = vec![];
+        let mut headers_end_stream = false;
+        let mut body = vec![];
+        loop {
+            // Until the first frame of the response arrives, failures happen while waiting.
+            let phase = match waiting_duration {
+                None => Phase::Waiting,
+                Some(_) => Phase::Downloading,
+            };
+            let frame = self.read_frame().await.map_err(|e| e.during(phase))?;
+            if frame.stream_id == stream_id && waiting_duration.is_none() {
+                waiting_duration = Some(start.elapsed());
+                start = Instant::now();
+            }
+            let mut end_stream = false;
+            match frame.kind {
+                HEADERS | CONTINUATION => {
+                    let mut fragment = frame.payload.as_slice();
+                    if frame.kind == HEADERS {
+                        fragment = strip_padding(fragment, frame.flags).ok_or_else(|| {
+                            self.invalid("invalid http2 frame padding".into())
+                                .during(phase)
+                        })?;
+                        if frame.flags & PRIORITY != 0 && fragment.len() >= 5 {
+                            fragment = &fragment[5..];
+                        }
+                        headers_end_stream = frame.flags & END_STREAM != 0;
+                    }
+                    header_block.extend_from_slice(fragment);
+                    if frame.flags & END_HEADERS != 0 {
+                        // Every header block has to be decoded to keep the compression context in sync.
+                        let decoded = self.decoder.decode(&header_block).map_err(|e| {
+                            self.invalid(format!("cannot decode http2 headers: {e:?}"))
+                                .during(phase)
+                        })?;
+                        header_block.clear();
+                        if frame.stream_id == stream_id {
+                            let decoded: Vec<(String, String)> = decoded
+                                .into_iter()
+                                .map(|(k, v)| {
+                                    (
+                                        String::from_utf8_lossy(&k).to_lowercase(),
+                                        String::from_utf8_lossy(&v).to_string(),
+                                    )
+                                })
+                                .collect();
+                            match response.as_mut() {
+                                // Trailers follow the body in a second header block.
+                                Some(response) => response.headers.extend(decoded),
+                                None => {
+                                    let status = decoded
+                                        .iter()
+                                        .find(|(k, _)| k == ":status")
+                                        .map(|(_, v)| v.clone())
+                                        .unwrap_or_default();
+                                    // Informational responses precede the final response.
+                                    if !status.starts_with('1') {
+                                        response = Some(HttpResponse::new(
+                                            "HTTP/2",
+                                            &status,
+                                            decoded
+                                                .into_iter()
+                                                .filter(|(k, _)| !k.starts_with(':'))
+                                                .collect(),
+                                        ));
+                                    }
+                                }
+                            }
+                            end_stream = headers_end_stream;
+                        }
+                    }
+                }
+                DATA => {
+                    let data = strip_padding(&frame.payload, frame.flags).ok_or_else(|| {
+                        self.invalid("invalid http2 frame padding".into())
+                            .during(phase)
+                    })?;
+                    if frame.stream_id == stream_id {
+                        body.extend_from_slice(data);
+                        end_stream = frame.flags & END_STREAM != 0;
+                    }
+                    if !frame.payload.is_empty() {
+                        let increment = (frame.payload.len() as u32).to_be_bytes();
+                        let mut payload = frame_bytes(WINDOW_UPDATE, 0, 0, &increment);
+                        if frame.stream_id == stream_id && !end_stream {
+                            payload.extend(frame_bytes(WINDOW_UPDATE, 0, stream_id, &increment));
+                        }
+                        self.write(&payload).await.map_err(|e| e.during(phase))?;
+                    }
+                }
+                // The server answered the stream, so it is not repeated on a new connection.
+                RST_STREAM if frame.stream_id == stream_id => {
+                    return Err(self.io_error(
+                        phase,
+                        io::Error::other(format!(
+                            "http2 stream reset by server with error code {}",
+                            read_u32(&frame.payload)
+                        )),
+                    ));
+                }
+                _ => {
+                    self.control(&frame, stream_id)
+                        .await
+                        .map_err(|e| e.during(phase))?;
+                }
+            }
+            if end_stream {
+                break;
+            }
+        }
+
+        let download_duration = start.elapsed();
+        let mut response = response
+            .ok_or_else(|| self.invalid("http2 stream ended without response headers".into()))?;
+        if is_debug {
+            println!("{} {}", response.version, response.status);
+            for (k, v) in &response.headers {
+                println!("{k}: {v}");
+            }
+            println!("\n{}", String::from_utf8_lossy(&body));
+        }
+        response.body = body;
+        response.received_bytes = self.received_bytes;
+        Ok((
+            sending_duration,
+            waiting_duration.unwrap_or_default(),
+            download_duration,
+            response,
+        ))
+    }
+
+    /// Sends the body as DATA frames within the flow control windows of the server.
+    async fn send_body(&mut self, stream_id: u32, mut body: &[u8]) -> Result<(), GarmataError> {
+        let mut stream_window = self.initial_send_window;
+        while !body.is_empty() {
+            let size = (body.len() as i64)
+                .min(self.max_frame_size as i64)
+                .min(self.send_window)
+                .min(stream_window);
+            if size <= 0 {
+                let frame = self.read_frame().await?;
+                stream_window += self.control(&frame, stream_id).await?;
+                continue;
+            }
+            let (chunk, rest) = body.split_at(size as usize);
+            let flags = if rest.is_empty() { END_STREAM } else { 0 };
+            self.write(&frame_bytes(DATA, flags, stream_id, chunk))
+                .await?;
+            self.send_window -= size;
+            stream_window -= size;
+            body = rest;
+        }
+        Ok(())
+    }
+
+    /// Handles connection level frames and returns the window increment for the given stream.
+    async fn control(&mut self, frame: &Frame, stream_id: u32) -> Result<i64, GarmataError> {
+        match frame.kind {
+            SETTINGS if frame.flags & ACK == 0 => {
+                for setting in frame.payload.chunks_exact(6) {
+                    let id = u16::from_be_bytes([setting[0], setting[1]]);
+                    let value = read_u32(&setting[2..]);
+                    match id {
+                        SETTINGS_INITIAL_WINDOW_SIZE => self.initial_send_window = value as i64,
+                        SETTINGS_MAX_FRAME_SIZE => {
+                            if !(16_384..=16_777_215).contains(&value) {
+                                return Err(
+                                    self.invalid(format!("invalid http2 max frame size {value}"))
+                                );
+                            }
+                            self.max_frame_size = value as usize;
+                        }
+                        _ => {}
+                    }
+                }
+                self.write(&frame_bytes(SETTINGS, ACK, 0, &[])).await?;
+            }
+            PING if frame.flags & ACK == 0 => {
+                self.write(&frame_bytes(PING, ACK, 0, &frame.payload))
+                    .await?;
+            }
+            WINDOW_UPDATE => {
+                let increment = (read_u32(&frame.payload) & 0x7fff_ffff) as i64;
+                if frame.stream_id == 0 {
+                    self.send_window += increment;
+                } else if frame.stream_id == stream_id {
+                    return Ok(increment);
+                }
+            }
+            GOAWAY => {
+                self.closed = true;
+                let last_stream_id = read_u32(&frame.payload) & 0x7fff_ffff;
+                if stream_id > last_stream_id {
+                    return Err(self.io_error(
+                        Phase::Downloading,
+                        io::Error::new(
+                            io::ErrorKind::ConnectionAborted,
+                            format!(
+                                "http2 connection closed by server with error code {}",
+                                read_u32(frame.payload.get(4..).unwrap_or_default())
+                            ),
+                        ),
+                    ));
+                }
+            }
+            PUSH_PROMISE => {
+                return Err(
+                    self.invalid("http2 server push received although it was disabled".into())
+                );
+            }
+            _ => {}
+        }
+        Ok(0)
+    }
+
+    async fn read_frame(&mut self) -> Result<Frame, GarmataError> {
+        let mut head = [0u8; 9];
+        if let Err(source) = self.stream.read_exact(&mut head).await {
+            return Err(self.io_error(Phase::Downloading, source));
+        }
+        let length = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+        let mut payload = vec![0u8; length];
+        if let Err(source) = self.stream.read_exact(&mut payload).await {
+            return Err(self.io_error(Phase::Downloading, source));
+        }
+        self.received_bytes += head.len() + length;
+        Ok(Frame {
+            kind: head[3],
+            flags: head[4],
+            stream_id: read_u32(&head[5..]) & 0x7fff_ffff,
+            payload,
+        })
+    }
+
+    async fn write(&mut self, payload: &[u8]) -> Result<(), GarmataError> {
+        let written = match self.stream.write_all(payload).await {
+            Ok(_) => self.stream.flush().await,
+            Err(e) => Err(e),
+        };
+        match written {
+            Ok(_) => Ok(()),
+            Err(source) => Err(self.io_error(Phase::Sending, source)),
+        }
+    }
+
+    fn io_error(&self, phase: Phase, source: io::Error) -> GarmataError {
+        GarmataError::io(&self.url, phase, source)
+    }
+
+    fn invalid(&self, reason: String) -> GarmataError {
+        GarmataError::Protocol {
+            url: self.url.clone(),
+            phase: Phase::Downloading,
+            reason,
+        }
+    }
+}
+
+fn frame_bytes(kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+    frame.push(kind);
+    frame.push(flags);
+    frame.extend_from_slice(&stream_id.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    match bytes.get(..4) {
+        Some(bytes) => u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        None => 0,
+    }
+}
+
+/// Payload without the padding or none if the padding is longer than the frame.
+fn strip_padding(payload: &[u8], flags: u8) -> Option<&[u8]> {
+    if flags & PADDED == 0 {
+        return Some(payload);
+    }
+    let padding = *payload.first().unwrap_or(&0) as usize;
+    match payload.len().checked_sub(padding) {
+        Some(end) if end >= 1 => Some(&payload[1..end]),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::{
+        net::{TcpListener, TcpStream},
+        task::{block_on, spawn},
+    };
+
+    /// Requests a path from a server that answers with the frames once the client connected.
+    fn exchange(frames: Vec<u8>) -> Result<HttpResponse, GarmataError> {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+            spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                stream.write_all(&frames).await.unwrap();
+                let mut received = vec![];
+                let _ = stream.read_to_end(&mut received).await;
+            });
+            let stream = TcpStream::connect(url.socket_addrs(|| None).unwrap()[0])
+                .await
+                .unwrap();
+            let mut connection = Http2Connection::handshake(Box::new(stream), &url).await?;
+            let request = HttpRequest::new("2", "GET", url);
+            let (.., response) = connection.request(&request, vec![], false).await?;
+            Ok(response)
+        })
+    }
+
+    fn settings(id: u16, value: u32) -> Vec<u8> {
+        let mut payload = id.to_be_bytes().to_vec();
+        payload.extend_from_slice(&value.to_be_bytes());
+        frame_bytes(SETTINGS, 0, 0, &payload)
+    }
+
+    fn headers(stream_id: u32, flags: u8, headers: &[(&[u8], &[u8])]) -> Vec<u8> {
+        let block = hpack::Encoder::new().encode(headers.iter().copied());
+        frame_bytes(HEADERS, flags | END_HEADERS, stream_id, &block)
+    }
+
+    #[test]
+    fn frame_layout() {
+        let frame = frame_bytes(DATA, END_STREAM, 3, b"ok");
+        assert_eq!(frame, [0, 0, 2, DATA, END_STREAM, 0, 0, 0, 3, b'o', b'k']);
+        assert_eq!(read_u32(&frame[5..9]), 3);
+        assert_eq!(read_u32(&[1, 2]), 0);
+    }
+
+    #[test]
+    fn padding() {
+        assert_eq!(strip_padding(b"\x02okxx", PADDED), Some(&b"ok"[..]));
+        assert_eq!(strip_padding(b"\x02okxx", 0), Some(&b"\x02okxx"[..]));
+        assert_eq!(strip_padding(b"\x05ok", PADDED), None);
+    }
+
+    #[test]
+    fn response_with_body() {
+        let mut frames = settings(SETTINGS_MAX_FRAME_SIZE, 1 << 20);
+        frames.extend(headers(1, 0, &[(b":status", b"200"), (b"Server", b"test")]));
+        frames.extend(frame_bytes(DATA, 0, 1, b"hello "));
+        frames.extend(frame_bytes(DATA, PADDED | END_STREAM, 1, b"\x01world!"));
+        let response = exchange(frames).unwrap();
+        assert_eq!(response.version, "HTTP/2");
+        assert_eq!(response.status, "200");
+        assert_eq!(response.header("server"), Some("test"));
+        assert_eq!(response.body, b"hello world");
+    }
+
+    #[test]
+    fn skips_informational_responses() {
+        let mut frames = headers(1, 0, &[(b":status", b"103")]);
+        frames.extend(headers(1, END_STREAM, &[(b":status", b"204")]));
+        let response = exchange(frames).unwrap();
+        assert_eq!(response.status, "204");
+        assert!(response.body.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_max_frame_size() {
+        for size in [0, 16_383, 1 << 24] {
+            match exchange(settings(SETTINGS_MAX_FRAME_SIZE, size)) {
+                Err(GarmataError::Protocol { reason, .. }) => {
+                    assert_eq!(reason, format!("invalid http2 max frame size {size}"))
+                }
+                Err(e) => panic!("unexpected error {e}"),
+                Ok(_) => panic!("max frame size {size} accepted"),
+            }
+        }
+    }
+
+    #[test]
+    fn stream_reset() {
+        let frames = frame_bytes(RST_STREAM, 0, 1, &7u32.to_be_bytes());
+        let error = exchange(frames).unwrap_err();
+        assert!(!error.is_closed_before_response());
+        assert!(error.to_string().ends_with("error code 7"));
+    }
+}
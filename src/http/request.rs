@@ -5,16 +5,16 @@ use super::cookies::Cookie;
 pub struct HttpRequest {
     http_version: String,
     headers: HashMap<String, String>,
-    method: String,
+    pub method: String,
     pub url: Url,
-    body: String,
+    pub body: String,
 }
 
 impl HttpRequest {
     pub fn headers(mut self, headers: &HashMap<String, String>) -> Self {
         for (k, v) in headers {
             let key = k.to_lowercase();
-            if !["host", "cookie"].contains(&key.as_str()) {
+            if !["host", "cookie", "content-length"].contains(&key.as_str()) {
                 self.headers.insert(key, v.to_string());
             }
         };
@@ -27,36 +27,79 @@ impl HttpRequest {
             .map(|(k, v)| format!("{k}: {v}\r\n"))
             .collect::<Vec<String>>()
             .join("");
-        let path_with_query = match self.url.query() {
-            Some(query) => self.url.path().to_string() + "?" + query,
-            None => self.url.path().to_string(),
-        };
         let cookies = if !cookies.is_empty() {
-            let cookies = cookies
-                .iter()
-                .map(|c| format!("{}={}", c.name, c.value))
-                .collect::<Vec<String>>()
-                .join("; ");
-            format!("cookie: {}\r\n", cookies)
+            format!("cookie: {}\r\n", cookie_header(cookies))
         } else {
             "".into()
         };
-        let optional_port = self.url
-            .port()
-            .map(|port| format!(":{port}"))
-            .unwrap_or_default();
+        // The body might be rendered from variables, so its length is only known here.
+        let content_length = if !self.body.is_empty() {
+            format!("content-length: {}\r\n", self.body.len())
+        } else {
+            "".into()
+        };
+        // Servers without HTTP/2 support get an HTTP/1.1 request instead.
+        let version = if self.is_http2() { "1.1" } else { &self.http_version };
         let payload = format!(
             "{method} {path_with_query} HTTP/{version}\r\n\
-            host: {hostname}{optional_port}\r\n\
-            {headers}{cookies}\r\n{body}",
-            version = self.http_version,
+            host: {authority}\r\n\
+            {headers}{content_length}{cookies}\r\n{body}",
             method = self.method.to_uppercase(),
-            hostname = self.url.host_str().unwrap(),
+            path_with_query = self.path_with_query(),
+            authority = self.authority(),
             body = self.body,
         );
         payload
     }
 
+    /// Header list of the request for HTTP/2 with the pseudo headers first.
+    /// Connection specific headers are not allowed in HTTP/2 and are left out.
+    pub fn http2_headers(&self, cookies: Vec<&Cookie>) -> Vec<(String, String)> {
+        let mut headers = vec![
+            (":method".to_string(), self.method.to_uppercase()),
+            (":scheme".to_string(), self.url.scheme().to_string()),
+            (":authority".to_string(), self.authority()),
+            (":path".to_string(), self.path_with_query()),
+        ];
+        for (k, v) in &self.headers {
+            if !["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"]
+                .contains(&k.as_str())
+            {
+                headers.push((k.clone(), v.clone()));
+            }
+        }
+        if !cookies.is_empty() {
+            headers.push(("cookie".to_string(), cookie_header(cookies)));
+        }
+        headers
+    }
+
+    pub fn is_http2(&self) -> bool {
+        self.http_version == "2"
+    }
+
+    fn path_with_query(&self) -> String {
+        match self.url.query() {
+            Some(query) => self.url.path().to_string() + "?" + query,
+            None => self.url.path().to_string(),
+        }
+    }
+
+    fn authority(&self) -> String {
+        let optional_port = self.url
+            .port()
+            .map(|port| format!(":{port}"))
+            .unwrap_or_default();
+        format!("{}{optional_port}", self.url.host_str().unwrap_or_default())
+    }
+
+    /// Asks the server to keep the connection open for further requests or to close it after the response.
+    pub fn keep_alive(mut self, keep_alive: bool) -> Self {
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+        self.headers.insert("connection".into(), connection.into());
+        self
+    }
+
     pub fn set_body(mut self, body: impl Into<String>) -> Self {
         self.body = body.into();
         self
@@ -76,3 +119,61 @@ impl HttpRequest {
         request
     }
 }
+
+fn cookie_header(cookies: Vec<&Cookie>) -> String {
+    cookies
+        .iter()
+        .map(|c| format!("{}={}", c.name, c.value))
+        .collect::<Vec<String>>()
+        .join("; ")
+}
+
+/// Replaces `{{name}}` placeholders with the variable values. Unknown placeholders are kept as they are.
+pub fn interpolate(template: &str, variables: &HashMap<String, String>) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        match rest[start..].find("}}") {
+            Some(end) => {
+                let placeholder = &rest[start..start + end + 2];
+                match variables.get(placeholder[2..placeholder.len() - 2].trim()) {
+                    Some(value) => rendered.push_str(value),
+                    None => rendered.push_str(placeholder),
+                }
+                rest = &rest[start + end + 2..];
+            }
+            None => {
+                rendered.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolates_known_variables() {
+        let variables = HashMap::from([
+            ("id".to_string(), "42".to_string()),
+            ("token".to_string(), "abc".to_string()),
+        ]);
+        assert_eq!(
+            interpolate("/users/{{id}}?t={{ token }}&again={{id}}", &variables),
+            "/users/42?t=abc&again=42"
+        );
+    }
+
+    #[test]
+    fn keeps_unknown_and_unclosed_placeholders() {
+        let variables = HashMap::from([("id".to_string(), "42".to_string())]);
+        assert_eq!(interpolate("{{missing}}/{{id}}", &variables), "{{missing}}/42");
+        assert_eq!(interpolate("{{id}}/{{id", &variables), "42/{{id");
+        assert_eq!(interpolate("{\"json\": {}}", &variables), "{\"json\": {}}");
+    }
+}
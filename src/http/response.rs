@@ -1,33 +1,290 @@
-use std::borrow::Cow;
+use async_std::io::{self, Read, ReadExt};
+use url::Url;
 
-use crate::GarmataError;
+use crate::error::{GarmataError, Phase};
 
 #[derive(Debug, Clone)]
 pub struct HttpResponse {
+    pub version: String,
     pub status: String,
     pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Bytes read from the connection including the head and the transfer encoding.
+    pub received_bytes: usize,
+    /// The body was delimited by the server closing the connection.
+    closed: bool,
 }
 
-impl TryFrom<Cow<'_, str>> for HttpResponse {
-    type Error = GarmataError;
+impl HttpResponse {
+    /// Reads one complete response. `received` holds the bytes already read from the stream.
+    /// The body is framed by Content-Length, chunked Transfer-Encoding or the end of the stream.
+    pub async fn read(
+        stream: &mut (dyn Read + Unpin + Send),
+        received: Vec<u8>,
+        has_body: bool,
+        url: &Url,
+    ) -> Result<Self, GarmataError> {
+        let mut reader = ResponseReader {
+            stream,
+            url,
+            received_bytes: received.len(),
+            buffer: received,
+        };
+        loop {
+            let mut response = reader.head().await?;
+            // Informational responses like 103 Early Hints precede the final response.
+            if response.status.starts_with('1') && response.status != "101" {
+                continue;
+            }
+            if !has_body || ["101", "204", "304"].contains(&response.status.as_str()) {
+                response.received_bytes = reader.received_bytes;
+                return Ok(response);
+            }
+
+            let transfer_encoding = response.header("transfer-encoding").map(str::to_lowercase);
+            let content_length = response.header("content-length").map(str::trim);
+            if transfer_encoding.is_some_and(|encoding| encoding.ends_with("chunked")) {
+                loop {
+                    let line = reader.line().await?;
+                    let size = line.split(';').next().unwrap_or_default().trim();
+                    let size = usize::from_str_radix(size, 16)
+                        .map_err(|_| reader.invalid(format!("invalid chunk size {size}")))?;
+                    if size == 0 {
+                        break;
+                    }
+                    response.body.extend(reader.take(size).await?);
+                    reader.line().await?;
+                }
+                loop {
+                    let trailer = reader.line().await?;
+                    if trailer.is_empty() {
+                        break;
+                    }
+                    if let Some((k, v)) = trailer.split_once(':') {
+                        response
+                            .headers
+                            .push((k.to_lowercase(), v.trim().to_string()));
+                    }
+                }
+            } else if let Some(content_length) = content_length {
+                let size = content_length.parse().map_err(|_| {
+                    reader.invalid(format!("invalid content-length {content_length}"))
+                })?;
+                response.body = reader.take(size).await?;
+            } else {
+                response.body = reader.rest().await?;
+                response.closed = true;
+            }
+            response.received_bytes = reader.received_bytes;
+            return Ok(response);
+        }
+    }
+
+    pub(crate) fn new(version: &str, status: &str, headers: Vec<(String, String)>) -> Self {
+        Self {
+            version: version.to_string(),
+            status: status.to_string(),
+            headers,
+            body: vec![],
+            received_bytes: 0,
+            closed: false,
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// HTTP/1.1 and HTTP/2 connections stay open unless the server closes them, HTTP/1.0 connections only on request.
+    pub fn keep_alive(&self) -> bool {
+        if self.closed {
+            return false;
+        }
+        match self.header("connection").map(str::to_lowercase) {
+            Some(connection) if connection.contains("close") => false,
+            Some(connection) if connection.contains("keep-alive") => true,
+            _ => ["HTTP/1.1", "HTTP/2"].contains(&self.version.as_str()),
+        }
+    }
+}
+
+struct ResponseReader<'a> {
+    stream: &'a mut (dyn Read + Unpin + Send),
+    url: &'a Url,
+    buffer: Vec<u8>,
+    received_bytes: usize,
+}
+
+impl ResponseReader<'_> {
+    /// Reads more bytes into the buffer and returns false once the server closed the stream.
+    async fn fill(&mut self) -> Result<bool, GarmataError> {
+        let mut chunk = [0u8; 8192];
+        match self.stream.read(&mut chunk).await {
+            Ok(size) => {
+                self.buffer.extend_from_slice(&chunk[..size]);
+                self.received_bytes += size;
+                Ok(size > 0)
+            }
+            Err(e) => Err(GarmataError::io(self.url, Phase::Downloading, e)),
+        }
+    }
 
-    fn try_from(value: Cow<str>) -> Result<Self, Self::Error> {
-        let header_body_split: Vec<(usize, &str)> = value.match_indices("\r\n\r\n").collect();
-        let body_start_index = match header_body_split.first() {
-            Some((body_start_index, _)) => body_start_index,
-            None => return Err(GarmataError { reason: "could not parse http response".into() }),
+    async fn head(&mut self) -> Result<HttpResponse, GarmataError> {
+        let end = loop {
+            if let Some(end) = self.buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+                break end;
+            }
+            if !self.fill().await? {
+                return Err(self.closed("connection closed within the response head".into()));
+            }
         };
-        let head: Vec<&str> = value[0..*body_start_index].split("\r\n").collect();
-        let mut response = HttpResponse {
-            status: head.first().unwrap().split(' ').nth(1).unwrap().to_string(),
-            headers: vec![],
+        let head: Vec<u8> = self.buffer.drain(..end + 4).collect();
+        let head = String::from_utf8_lossy(&head[..end]);
+        let mut lines = head.split("\r\n");
+        let mut status_line = lines.next().unwrap_or_default().split(' ');
+        let (Some(version), Some(status)) = (status_line.next(), status_line.next()) else {
+            return Err(self.invalid("could not parse http response status line".into()));
         };
-        for entry in head.iter().skip(1) {
-            let mut key_val = entry.split(": ");
-            if let (Some(k), Some(v)) = (key_val.next(), key_val.next()) {
-                response.headers.push((k.to_lowercase(), v.to_string()));
+        let mut response = HttpResponse::new(version, status, vec![]);
+        for entry in lines {
+            if let Some((k, v)) = entry.split_once(':') {
+                response
+                    .headers
+                    .push((k.to_lowercase(), v.trim().to_string()));
             }
         }
         Ok(response)
     }
+
+    async fn line(&mut self) -> Result<String, GarmataError> {
+        loop {
+            if let Some(end) = self.buffer.windows(2).position(|w| w == b"\r\n") {
+                let line: Vec<u8> = self.buffer.drain(..end + 2).collect();
+                return Ok(String::from_utf8_lossy(&line[..end]).to_string());
+            }
+            if !self.fill().await? {
+                return Err(self.closed("connection closed within a chunked body".into()));
+            }
+        }
+    }
+
+    async fn take(&mut self, size: usize) -> Result<Vec<u8>, GarmataError> {
+        while self.buffer.len() < size {
+            if !self.fill().await? {
+                return Err(self.closed(format!(
+                    "connection closed after {} of {size} body bytes",
+                    self.buffer.len()
+                )));
+            }
+        }
+        Ok(self.buffer.drain(..size).collect())
+    }
+
+    async fn rest(&mut self) -> Result<Vec<u8>, GarmataError> {
+        while self.fill().await? {}
+        Ok(std::mem::take(&mut self.buffer))
+    }
+
+    fn closed(&self, reason: String) -> GarmataError {
+        GarmataError::Io {
+            url: self.url.clone(),
+            phase: Phase::Downloading,
+            source: io::Error::new(io::ErrorKind::UnexpectedEof, reason),
+        }
+    }
+
+    fn invalid(&self, reason: String) -> GarmataError {
+        GarmataError::Protocol {
+            url: self.url.clone(),
+            phase: Phase::Downloading,
+            reason,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::{io::Cursor, task::block_on};
+
+    fn read(raw: &str, has_body: bool) -> Result<HttpResponse, GarmataError> {
+        let url = Url::parse("http://localhost/").unwrap();
+        let mut stream = Cursor::new(raw.as_bytes().to_vec());
+        block_on(HttpResponse::read(&mut stream, vec![], has_body, &url))
+    }
+
+    #[test]
+    fn content_length() {
+        let raw = "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhelloHTTP/1.1";
+        let response = read(raw, true).unwrap();
+        assert_eq!(response.version, "HTTP/1.1");
+        assert_eq!(response.status, "200");
+        assert_eq!(response.header("content-length"), Some("5"));
+        assert_eq!(response.body, b"hello");
+        assert_eq!(response.received_bytes, raw.len());
+        assert!(response.keep_alive());
+    }
+
+    #[test]
+    fn chunked_with_trailers() {
+        let raw = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nChecksum: abc\r\n\r\n";
+        let response = read(raw, true).unwrap();
+        assert_eq!(response.body, b"hello world");
+        assert_eq!(response.header("checksum"), Some("abc"));
+        assert!(response.keep_alive());
+    }
+
+    #[test]
+    fn invalid_chunk_size() {
+        let raw = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nxyz\r\nhello\r\n0\r\n\r\n";
+        match read(raw, true) {
+            Err(GarmataError::Protocol { reason, .. }) => {
+                assert_eq!(reason, "invalid chunk size xyz")
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn body_until_the_end_of_the_stream() {
+        let response = read("HTTP/1.0 200 OK\r\n\r\nall of it", true).unwrap();
+        assert_eq!(response.body, b"all of it");
+        assert!(!response.keep_alive());
+    }
+
+    #[test]
+    fn skips_informational_responses() {
+        let raw = "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 103 Early Hints\r\nLink: </a.css>\r\n\r\nHTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok";
+        let response = read(raw, true).unwrap();
+        assert_eq!(response.status, "201");
+        assert_eq!(response.header("link"), None);
+        assert_eq!(response.body, b"ok");
+    }
+
+    #[test]
+    fn responses_without_a_body() {
+        let head = read("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n", false).unwrap();
+        assert!(head.body.is_empty());
+        for status in ["204 No Content", "304 Not Modified"] {
+            let raw = format!("HTTP/1.1 {status}\r\nContent-Length: 5\r\n\r\n");
+            let response = read(&raw, true).unwrap();
+            assert!(response.body.is_empty());
+            assert!(response.keep_alive());
+        }
+    }
+
+    #[test]
+    fn closed_before_the_end_of_the_body() {
+        let raw = "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort";
+        assert!(matches!(
+            read(raw, true),
+            Err(GarmataError::Io {
+                phase: Phase::Downloading,
+                ..
+            })
+        ));
+    }
 }
@@ -0,0 +1,146 @@
+use async_std::io::{self, Read, ReadExt, Write, WriteExt};
+use native_tls::{HandshakeError, TlsConnector};
+use std::{
+    io::{Read as _, Write as _},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+/// TLS stream over an async socket.
+/// native-tls only works on blocking streams, so it reads and writes memory buffers
+/// that are filled from and flushed to the socket asynchronously.
+pub(crate) struct TlsStream<S> {
+    tls: native_tls::TlsStream<Buffers>,
+    socket: S,
+}
+
+#[derive(Default)]
+struct Buffers {
+    incoming: Vec<u8>,
+    outgoing: Vec<u8>,
+    /// The socket was closed by the server.
+    closed: bool,
+}
+
+impl std::io::Read for Buffers {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.incoming.is_empty() && !self.closed {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let size = buf.len().min(self.incoming.len());
+        buf[..size].copy_from_slice(&self.incoming[..size]);
+        self.incoming.drain(..size);
+        Ok(size)
+    }
+}
+
+impl std::io::Write for Buffers {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.outgoing.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Performs the handshake. Every time native-tls runs out of input the pending
+/// output is sent and the next bytes of the server are awaited.
+pub(crate) async fn connect<S: Read + Write + Unpin>(
+    connector: &TlsConnector,
+    domain: &str,
+    mut socket: S,
+) -> io::Result<TlsStream<S>> {
+    let mut handshake = connector.connect(domain, Buffers::default());
+    loop {
+        match handshake {
+            Ok(mut tls) => {
+                let outgoing = std::mem::take(&mut tls.get_mut().outgoing);
+                socket.write_all(&outgoing).await?;
+                socket.flush().await?;
+                return Ok(TlsStream { tls, socket });
+            }
+            Err(HandshakeError::Failure(e)) => return Err(io::Error::other(e)),
+            Err(HandshakeError::WouldBlock(mut pending)) => {
+                let buffers = pending.get_mut();
+                socket
+                    .write_all(&std::mem::take(&mut buffers.outgoing))
+                    .await?;
+                socket.flush().await?;
+                let mut chunk = [0u8; 16_384];
+                let size = socket.read(&mut chunk).await?;
+                buffers.incoming.extend_from_slice(&chunk[..size]);
+                buffers.closed = size == 0;
+                handshake = pending.handshake();
+            }
+        }
+    }
+}
+
+impl<S> TlsStream<S> {
+    /// Protocol the server selected with ALPN.
+    pub fn negotiated_alpn(&self) -> Option<Vec<u8>> {
+        self.tls.negotiated_alpn().ok().flatten()
+    }
+}
+
+impl<S: Write + Unpin> TlsStream<S> {
+    /// Sends the encrypted bytes native-tls has written so far.
+    fn poll_outgoing(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        let outgoing = &mut self.tls.get_mut().outgoing;
+        while !outgoing.is_empty() {
+            let size = ready!(Pin::new(&mut self.socket).poll_write(cx, outgoing))?;
+            if size == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            outgoing.drain(..size);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: Read + Write + Unpin> Read for TlsStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            ready!(this.poll_outgoing(cx))?;
+            match this.tls.read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    ready!(this.poll_outgoing(cx))?;
+                    let mut chunk = [0u8; 16_384];
+                    let size = ready!(Pin::new(&mut this.socket).poll_read(cx, &mut chunk))?;
+                    let buffers = this.tls.get_mut();
+                    buffers.incoming.extend_from_slice(&chunk[..size]);
+                    buffers.closed = size == 0;
+                }
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+}
+
+impl<S: Write + Unpin> Write for TlsStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // Encrypt the next bytes only once the previous ones left, to keep the buffer small.
+        ready!(this.poll_outgoing(cx))?;
+        Poll::Ready(this.tls.write(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_outgoing(cx))?;
+        Pin::new(&mut this.socket).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_outgoing(cx))?;
+        Pin::new(&mut this.socket).poll_close(cx)
+    }
+}
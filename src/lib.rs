@@ -1,188 +1,702 @@
 // MIT License - free as in freedom; Full license in the LICENSE file
+pub mod checks;
+pub mod compare;
 pub mod configuration;
+pub mod data;
+pub mod error;
+pub mod extract;
 pub mod http;
+pub mod metrics;
+pub mod progress;
+pub mod report;
+pub mod sinks;
+pub mod summary;
+pub mod thresholds;
 
-use async_std::task::spawn;
+use async_io::Timer;
+use async_std::{
+    channel::bounded,
+    future,
+    io::{self, Read, ReadExt, Write, WriteExt},
+    net::TcpStream,
+    task::{sleep, spawn, yield_now},
+};
+use async_std_resolver::{resolver_from_system_conf, AsyncStdResolver, ResolveError};
 use configuration::*;
+use data::Feeder;
+pub use error::GarmataError;
+use error::Phase;
 use http::cookies::{Cookie, Cookiejar};
-use http::request::HttpRequest;
+use http::http2::Http2Connection;
+use http::request::{interpolate, HttpRequest};
 use http::response::HttpResponse;
+use http::tls;
 use http::*;
 use native_tls::TlsConnector;
+use sinks::{PushSink, ResultSink};
 use std::{
-    io::{Read, Write},
-    net::{IpAddr, TcpStream},
+    collections::HashMap,
+    future::{poll_fn, Future},
+    net::IpAddr,
+    pin::{pin, Pin},
     str::FromStr,
-    sync::{Arc, RwLock},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    task::{ready, Context, Poll},
     time::{Duration, Instant},
 };
-use trust_dns_resolver::Resolver as DnsResolver;
+use summary::Summary;
 use url::Url;
 
-trait ReadAndWrite: Write + Read {}
-impl<T: Write + Read> ReadAndWrite for T {}
+trait ReadAndWrite: Write + Read + Send + Unpin {}
+impl<T: Write + Read + Send + Unpin> ReadAndWrite for T {}
+
+enum Protocol {
+    Http1(Box<dyn ReadAndWrite>),
+    Http2(Box<Http2Connection>),
+}
 
-#[derive(Debug)]
-pub struct GarmataError {
-    pub reason: String,
+struct Connection {
+    protocol: Protocol,
+    /// Limits of the TCP stream below TLS and HTTP/2, updated for every request.
+    limits: Arc<Mutex<Limits>>,
 }
 
-pub async fn run(config: String, is_debug: bool) -> Result<Vec<HttpResult>, GarmataError> {
-    let config: Configuration = match std::fs::File::open(&config) {
-        Ok(file) => match serde_yaml::from_reader(file) {
-            Ok(config) => config,
-            Err(e) => {
-                return Err(GarmataError {
-                    reason: format!("cannot parse {config}: {e}"),
-                })
+impl Connection {
+    async fn request(
+        &mut self,
+        http_request: &HttpRequest,
+        cookies: Vec<&Cookie>,
+        is_debug: bool,
+    ) -> Result<(Duration, Duration, Duration, HttpResponse), GarmataError> {
+        match &mut self.protocol {
+            Protocol::Http1(stream) => request(stream, http_request, cookies, is_debug).await,
+            Protocol::Http2(connection) => {
+                connection.request(http_request, cookies, is_debug).await
             }
-        },
-        Err(e) => {
-            return Err(GarmataError {
-                reason: e.to_string(),
-            })
         }
-    };
+    }
+
+    fn is_reusable(&self, response: &HttpResponse) -> bool {
+        match &self.protocol {
+            Protocol::Http1(_) => response.keep_alive(),
+            Protocol::Http2(connection) => !connection.closed,
+        }
+    }
+
+    fn limit(&self, read_timeout: Option<Duration>, deadline: Option<Instant>) {
+        *self.limits.lock().unwrap() = Limits {
+            read_timeout,
+            deadline,
+        };
+    }
+}
 
+/// Read timeout and deadline of the current request on a connection.
+#[derive(Debug, Default)]
+struct Limits {
+    read_timeout: Option<Duration>,
+    deadline: Option<Instant>,
+}
+
+/// TCP stream that applies the limits of the current request to every read and write.
+struct TimedStream {
+    stream: TcpStream,
+    limits: Arc<Mutex<Limits>>,
+    /// Fires once the pending read or write exceeds the limits.
+    timer: Option<Timer>,
+}
+
+impl TimedStream {
+    /// Polls a read or write of the stream and fails it with a timeout once the limits are exceeded.
+    fn poll_limited<T>(
+        &mut self,
+        cx: &mut Context,
+        poll: impl FnOnce(Pin<&mut TcpStream>, &mut Context) -> Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        let (read_timeout, deadline) = {
+            let limits = self.limits.lock().unwrap();
+            (limits.read_timeout, limits.deadline)
+        };
+        if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+            self.timer = None;
+            return Poll::Ready(Err(io::ErrorKind::TimedOut.into()));
+        }
+        if let Poll::Ready(result) = poll(Pin::new(&mut self.stream), cx) {
+            self.timer = None;
+            return Poll::Ready(result);
+        }
+        let timer = match &mut self.timer {
+            Some(timer) => timer,
+            None => {
+                let expiry = read_timeout
+                    .map(|read_timeout| Instant::now() + read_timeout)
+                    .into_iter()
+                    .chain(deadline)
+                    .min();
+                match expiry {
+                    Some(expiry) => self.timer.insert(Timer::at(expiry)),
+                    None => return Poll::Pending,
+                }
+            }
+        };
+        ready!(Pin::new(timer).poll(cx));
+        self.timer = None;
+        Poll::Ready(Err(io::ErrorKind::TimedOut.into()))
+    }
+}
+
+impl Read for TimedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut()
+            .poll_limited(cx, |stream, cx| stream.poll_read(cx, buf))
+    }
+}
+
+impl Write for TimedStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.get_mut()
+            .poll_limited(cx, |stream, cx| stream.poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_close(cx)
+    }
+}
+
+/// Resolver shared by all users of a run, so lookups share its cache and the system configuration is read once.
+/// It is created on the first lookup of a host name, runs against ip addresses need no dns configuration.
+#[derive(Clone, Default)]
+struct Resolver(Arc<async_std::sync::Mutex<Option<AsyncStdResolver>>>);
+
+impl Resolver {
+    async fn get(&self) -> Result<AsyncStdResolver, ResolveError> {
+        let mut resolver = self.0.lock().await;
+        if let Some(resolver) = &*resolver {
+            return Ok(resolver.clone());
+        }
+        let created = resolver_from_system_conf().await?;
+        *resolver = Some(created.clone());
+        Ok(created)
+    }
+}
+
+/// State of one virtual user that outlives a single flow or iteration.
+#[derive(Default)]
+struct VirtualUser {
+    /// Idle keep-alive connections keyed by scheme, host, port and whether certificates were verified.
+    connections: HashMap<String, Connection>,
+    /// Values extracted from responses for the `{{name}}` placeholders of later flows.
+    variables: HashMap<String, String>,
+    cookiejar: Cookiejar,
+    /// Index of the data row the user currently works with.
+    row: Option<usize>,
+    /// The data rows ran out and the user must not start further iterations.
+    stopped: bool,
+}
+
+/// Outcome of a test run: the statistics of all results and the iterations a rate limited group had to drop.
+pub struct Report {
+    pub summary: Summary,
+    pub dropped_iterations: HashMap<String, usize>,
+    pub duration: Duration,
+}
+
+/// State of a running test that others can watch while it runs.
+#[derive(Default)]
+pub struct RunState {
+    /// Virtual users in the middle of an iteration.
+    pub active_users: AtomicUsize,
+    /// Set once the whole test has to stop, no group starts further iterations.
+    pub stop: Arc<AtomicBool>,
+    /// Set with stop to end the run after the grace period even if iterations are still in flight.
+    pub interrupted: AtomicBool,
+}
+
+/// Runs the test. Every result is recorded in the summary of the report and handed to the sinks as it arrives.
+pub async fn run(
+    config: Configuration,
+    is_debug: bool,
+    mut sinks: Vec<Box<dyn ResultSink>>,
+    state: Arc<RunState>,
+) -> Result<Report, GarmataError> {
+    for sink in &config.sinks {
+        sinks.push(Box::new(PushSink::new(sink)?));
+    }
+    let start = Instant::now();
     let mut all_groups = vec![];
-    let results = Arc::new(RwLock::new(Vec::new()));
-    for group in config.groups {
+    // Results are aggregated online, so the memory of a run does not grow with its duration.
+    let (results, received_results) = bounded::<HttpResult>(1024);
+    let mut summary = Summary::new(
+        config.interval.map(Duration::from_secs),
+        config.percentiles.clone(),
+    );
+    let aggregator = spawn(async move {
+        while let Ok(result) = received_results.recv().await {
+            summary.record(&result, start.elapsed());
+            sinks.retain_mut(|sink| match sink.write(&result) {
+                Ok(_) => true,
+                Err(e) => {
+                    eprintln!("cannot write results: {e}");
+                    false
+                }
+            });
+        }
+        summary.finish();
+        for sink in &mut sinks {
+            if let Err(e) = sink.finish() {
+                eprintln!("cannot write results: {e}");
+            }
+        }
+        summary
+    });
+    let feeder = match &config.data {
+        Some(data) => Some(Arc::new(Feeder::load(data)?)),
+        None => None,
+    };
+    let resolver = Resolver::default();
+    let stop = state.stop.clone();
+    let grace_period = Duration::from_secs(config.grace_period);
+    for mut group in config.groups {
+        // HTTP/2 multiplexes the requests of a user over one connection unless keep-alive is disabled explicitly.
+        let keep_alive = group.keep_alive.unwrap_or(config.http_version == "2");
+        let timeouts = group.timeouts.or(config.timeouts);
+        for flow in &mut group.flows {
+            flow.keep_alive = flow.keep_alive.or(Some(keep_alive));
+            flow.timeouts = flow.timeouts.or(timeouts);
+        }
         let scheme = config.scheme.clone();
         let target = config.target.clone();
         let http_version = config.http_version.clone();
+        let resolver = resolver.clone();
         let results = results.clone();
-        let deadline = Instant::now()
-            .checked_add(Duration::from_secs(group.duration))
-            .expect(&format!(
-                "invalid duration provided for group {}",
+        let duration = if group.stages.is_empty() {
+            group.duration
+        } else {
+            group.stages.iter().map(|stage| stage.duration).sum()
+        };
+        let deadline = match Instant::now().checked_add(Duration::from_secs(duration)) {
+            Some(deadline) => deadline,
+            None => {
+                return Err(GarmataError::Configuration(format!(
+                    "invalid duration provided for group {}",
+                    &group.name
+                )))
+            }
+        };
+        if group
+            .rate
+            .is_some_and(|rate| !rate.is_normal() || rate < 0.0)
+        {
+            return Err(GarmataError::Configuration(format!(
+                "invalid rate provided for group {}",
                 &group.name
-            ));
+            )));
+        }
+        if group.rate.is_some() && !group.stages.is_empty() {
+            return Err(GarmataError::Configuration(format!(
+                "group {} cannot have a rate and stages",
+                &group.name
+            )));
+        }
+        if group.rate.is_some() && group.users == 0 {
+            return Err(GarmataError::Configuration(format!(
+                "group {} needs at least one user for its rate",
+                &group.name
+            )));
+        }
+        let group_name = group.name.clone();
+        let current_stage = Arc::new(RwLock::new(String::new()));
+        // Errors that stop a flow before its request, like a failed interpolation, repeat in every iteration.
+        let reported: Arc<Vec<AtomicBool>> =
+            Arc::new(group.flows.iter().map(|_| AtomicBool::new(false)).collect());
+        let feeder = feeder.clone();
+        let stop = stop.clone();
+        let state = state.clone();
         let handle = spawn(async move {
-            loop {
-                let mut all_user_flows = vec![];
-                for _ in 0..group.users {
-                    let http_version = http_version.clone();
-                    let scheme = scheme.clone();
-                    let target = target.clone();
-                    let group_name = group.name.clone();
-                    let results = results.clone();
-                    let flows = group.flows.clone();
-                    let handle = spawn(async move {
-                        for flow in &flows {
-                            match execute(
-                                &http_version,
-                                &scheme,
-                                &target,
-                                flow,
-                                &group_name,
-                                is_debug,
-                            ) {
-                                Ok(result) => results.write().unwrap().push(result),
-                                Err(e) => {
-                                    eprintln!("{}", e.reason);
+            let stage = current_stage.clone();
+            let stop_test = stop.clone();
+            let cookie_jar = group.cookie_jar;
+            let iteration = move |mut user: VirtualUser| {
+                let http_version = http_version.clone();
+                let scheme = scheme.clone();
+                let target = target.clone();
+                let resolver = resolver.clone();
+                let group_name = group_name.clone();
+                let results = results.clone();
+                let flows = group.flows.clone();
+                let reported = reported.clone();
+                let stage = stage.clone();
+                let feeder = feeder.clone();
+                let stop = stop_test.clone();
+                let state = state.clone();
+                async move {
+                    if cookie_jar == CookieJarScope::Iteration {
+                        user.cookiejar = Cookiejar::default();
+                    }
+                    if let Some(feeder) = feeder {
+                        match feeder.next(user.row) {
+                            Some(row) => {
+                                user.row = Some(row);
+                                user.variables.extend(feeder.row(row).clone());
+                            }
+                            None => {
+                                user.stopped = true;
+                                if feeder.on_exhausted == Exhausted::StopTest {
+                                    stop.store(true, Ordering::SeqCst);
+                                }
+                                return user;
+                            }
+                        }
+                    }
+                    state.active_users.fetch_add(1, Ordering::SeqCst);
+                    for (index, flow) in flows.iter().enumerate() {
+                        match execute(
+                            &http_version,
+                            &scheme,
+                            &target,
+                            &resolver,
+                            flow,
+                            &group_name,
+                            &mut user,
+                            is_debug,
+                        )
+                        .await
+                        {
+                            Ok(mut result) => {
+                                result.stage = stage.read().unwrap().clone();
+                                // Later flows of the iteration might depend on the failed one.
+                                let failed = result.error.is_some();
+                                let _ = results.send(result).await;
+                                if failed {
                                     break;
                                 }
                             }
+                            Err(e) => {
+                                if !reported[index].swap(true, Ordering::Relaxed) {
+                                    eprintln!("{e}, not shown again for this flow");
+                                }
+                                break;
+                            }
                         }
-                    });
-                    all_user_flows.push(handle);
+                    }
+                    state.active_users.fetch_sub(1, Ordering::SeqCst);
+                    user
                 }
-                for user_flow in all_user_flows {
-                    user_flow.await
+            };
+            // Staged closed model: users are added and removed on the fly to follow the stages.
+            // Users above the current target finish their iteration before they leave.
+            if !group.stages.is_empty() {
+                let start = Instant::now();
+                let target_users = Arc::new(AtomicUsize::new(0));
+                let mut active_users: Vec<Arc<AtomicBool>> = vec![];
+                let mut all_users = vec![];
+                while Instant::now() < deadline && !stop.load(Ordering::SeqCst) {
+                    let (stage, users) = stage_at(&group.stages, start.elapsed());
+                    *current_stage.write().unwrap() = stage;
+                    target_users.store(users, Ordering::SeqCst);
+                    for id in 0..users {
+                        if active_users.len() <= id {
+                            active_users.push(Arc::new(AtomicBool::new(false)));
+                        }
+                        if !active_users[id].swap(true, Ordering::SeqCst) {
+                            let active = active_users[id].clone();
+                            let target_users = target_users.clone();
+                            let iteration = iteration.clone();
+                            let stop = stop.clone();
+                            all_users.push(spawn(async move {
+                                let mut user = VirtualUser::default();
+                                while id < target_users.load(Ordering::SeqCst)
+                                    && Instant::now() < deadline
+                                    && !stop.load(Ordering::SeqCst)
+                                {
+                                    user = iteration(user).await;
+                                    if user.stopped {
+                                        // Stopped users stay active to not be replaced by a new user.
+                                        return;
+                                    }
+                                    yield_now().await;
+                                }
+                                active.store(false, Ordering::SeqCst);
+                            }));
+                        }
+                    }
+                    sleep(Duration::from_millis(100)).await;
                 }
-                if Instant::now() >= deadline {
-                    break;
+                for user in all_users {
+                    user.await
+                }
+                return (group.name, 0);
+            }
+            match group.rate {
+                // Open model: iterations start on a fixed timeline, independent of response times.
+                // The idle users channel caps the iterations in flight at the amount of users.
+                Some(rate) => {
+                    let (idle_users, available_users) = bounded(group.users);
+                    for _ in 0..group.users {
+                        idle_users.send(VirtualUser::default()).await.unwrap();
+                    }
+                    let alive_users = Arc::new(AtomicUsize::new(group.users));
+                    let start = Instant::now();
+                    let mut dropped = 0;
+                    for tick in 0u64.. {
+                        // With tiny rates the next tick lies beyond any representable time.
+                        let Some(scheduled) = Duration::try_from_secs_f64(tick as f64 / rate)
+                            .ok()
+                            .and_then(|offset| start.checked_add(offset))
+                        else {
+                            break;
+                        };
+                        if (tick > 0 && scheduled >= deadline)
+                            || stop.load(Ordering::SeqCst)
+                            || alive_users.load(Ordering::SeqCst) == 0
+                        {
+                            break;
+                        }
+                        let now = Instant::now();
+                        if scheduled > now {
+                            sleep(scheduled - now).await;
+                        }
+                        match available_users.try_recv() {
+                            Ok(user) => {
+                                let idle_users = idle_users.clone();
+                                let alive_users = alive_users.clone();
+                                let iteration = iteration(user);
+                                spawn(async move {
+                                    let user = iteration.await;
+                                    if user.stopped {
+                                        alive_users.fetch_sub(1, Ordering::SeqCst);
+                                    } else {
+                                        idle_users.send(user).await.unwrap();
+                                    }
+                                });
+                            }
+                            Err(_) => dropped += 1,
+                        }
+                    }
+                    // The channel closes once the last iteration in flight returned its user.
+                    drop(idle_users);
+                    while available_users.recv().await.is_ok() {}
+                    (group.name, dropped)
+                }
+                // Closed model: every user repeats its flows as soon as the previous iteration finished.
+                None => {
+                    let mut users: Vec<VirtualUser> =
+                        (0..group.users).map(|_| VirtualUser::default()).collect();
+                    loop {
+                        let mut all_user_flows = vec![];
+                        for user in users.drain(..) {
+                            all_user_flows.push(spawn(iteration(user)));
+                        }
+                        for user_flow in all_user_flows {
+                            let user = user_flow.await;
+                            if !user.stopped {
+                                users.push(user);
+                            }
+                        }
+                        if Instant::now() >= deadline
+                            || stop.load(Ordering::SeqCst)
+                            || users.is_empty()
+                        {
+                            break;
+                        }
+                    }
+                    (group.name, 0)
                 }
             }
         });
         all_groups.push(handle);
     }
 
-    for group in all_groups {
-        group.await
+    let mut dropped_iterations = HashMap::new();
+    // Groups end once their iterations finished, or after the grace period once the run is interrupted.
+    {
+        let mut groups = pin!(async {
+            for group in all_groups {
+                let (name, dropped) = group.await;
+                *dropped_iterations.entry(name).or_default() += dropped;
+            }
+        });
+        let mut grace = pin!(async {
+            while !state.interrupted.load(Ordering::SeqCst) {
+                sleep(Duration::from_millis(100)).await;
+            }
+            sleep(grace_period).await;
+        });
+        poll_fn(|cx| match groups.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(()),
+            Poll::Pending => grace.as_mut().poll(cx),
+        })
+        .await;
     }
-    let results = results.read().unwrap().as_slice().to_vec();
-    Ok(results)
+    // The aggregator finishes once the results are closed, iterations still in flight after the grace period are lost.
+    results.close();
+    Ok(Report {
+        summary: aggregator.await,
+        dropped_iterations,
+        duration: start.elapsed(),
+    })
 }
 
-fn execute(
+/// Name of the stage active after the elapsed time and its user target.
+/// The target is interpolated linearly from the previous stage target, starting at zero users.
+fn stage_at(stages: &[Stage], elapsed: Duration) -> (String, usize) {
+    let mut stage_start = Duration::default();
+    let mut previous_users = 0;
+    for (index, stage) in stages.iter().enumerate() {
+        let stage_duration = Duration::from_secs(stage.duration);
+        let name = if stage.name.is_empty() {
+            (index + 1).to_string()
+        } else {
+            stage.name.clone()
+        };
+        if elapsed < stage_start + stage_duration || index == stages.len() - 1 {
+            let progress = ((elapsed.saturating_sub(stage_start)).as_secs_f64()
+                / stage_duration.as_secs_f64())
+            .min(1.0);
+            let users =
+                previous_users as f64 + (stage.users as f64 - previous_users as f64) * progress;
+            return (name, users.round() as usize);
+        }
+        stage_start += stage_duration;
+        previous_users = stage.users;
+    }
+    (String::new(), 0)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn execute(
     http_version: &str,
     scheme: &String,
     target: &String,
+    resolver: &Resolver,
     flow: &Flow,
     group_name: &str,
+    user: &mut VirtualUser,
     is_debug: bool,
 ) -> Result<HttpResult, GarmataError> {
+    let keep_alive = flow.keep_alive.unwrap_or_default();
     let mut method = flow.method.clone();
-    let start_timestamp = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
-    let url = match Url::parse(&format!("{scheme}://{target}{}", flow.path)) {
-        Ok(url) => url,
+    let start = Instant::now();
+    let read_timeout = flow.timeouts.read_timeout.map(Duration::from_millis);
+    let deadline = flow
+        .timeouts
+        .request_timeout
+        .map(|timeout| start + Duration::from_millis(timeout));
+    let mut result = HttpResult {
+        group: group_name.to_owned(),
+        flow: flow.name.clone(),
+        start_timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        ..Default::default()
+    };
+    let variables = &user.variables;
+    let path = interpolate(&flow.path, variables);
+    let url = match Url::parse(&format!("{scheme}://{target}{path}")) {
+        Ok(url) if url.host_str().is_some() && url.port_or_known_default().is_some() => url,
+        Ok(url) => {
+            return Err(GarmataError::Configuration(format!(
+                "unsupported url {url} in flow {}",
+                flow.name
+            )))
+        }
         Err(e) => {
-            return Err(GarmataError {
-                reason: e.to_string(),
-            })
+            return Err(GarmataError::Configuration(format!(
+                "invalid url {scheme}://{target}{path} in flow {}: {e}",
+                flow.name
+            )))
         }
     };
 
     let mut redirects = 0;
-    let mut redirect_duration = Duration::from_secs(0);
     let mut http_request = HttpRequest::new(http_version, &method, url)
-        .headers(&flow.headers)
-        .set_body(&flow.body);
-    let mut cookiejar = Cookiejar::default();
-    cookiejar.set_all(
-        &flow.cookies,
-        http_request.url.host_str().unwrap().to_string(),
+        .keep_alive(keep_alive)
+        .headers(
+            &flow
+                .headers
+                .iter()
+                .map(|(k, v)| (k.clone(), interpolate(v, variables)))
+                .collect(),
+        )
+        .set_body(interpolate(&flow.body, variables));
+    user.cookiejar.set_all(
+        &flow
+            .cookies
+            .iter()
+            .map(|cookie| interpolate(cookie, variables))
+            .collect(),
+        http_request.url.host_str().unwrap_or_default().to_string(),
     );
 
     loop {
-        let (addr, dns_duration) = dns_resolve(&http_request.url)?;
-        let port = http_request.url.port_or_known_default().unwrap();
-        let (stream, connect_duration) = tcp_connect(addr, port)?;
-        let (mut stream, tls_duration) = tls_handshake(stream, &http_request.url, flow.insecure)?;
-
-        let (sending_duration, waiting_duration, download_duration, response) = request(
-            &mut stream,
-            &http_request,
-            cookiejar.get(http_request.url.host_str(), http_request.url.path()),
-            is_debug,
-        )?;
+        let port = http_request.url.port_or_known_default().unwrap_or_default();
+        // Connections of flows that skip certificate verification are never reused by strict ones.
+        let connection_key = format!(
+            "{}://{}:{port} insecure={}",
+            http_request.url.scheme(),
+            http_request.url.host_str().unwrap_or_default(),
+            flow.insecure
+        );
+        let cookies = user
+            .cookiejar
+            .get(http_request.url.host_str(), http_request.url.path());
 
-        if redirects == flow.max_redirects
-            || !["301", "302", "303", "307", "308"].contains(&response.status.as_str())
-        {
-            return Ok(HttpResult {
-                group: group_name.to_owned(),
-                flow: flow.name.clone(),
-                start_timestamp,
-                dns_duration,
-                connect_duration,
-                tls_duration,
-                sending_duration,
-                waiting_duration,
-                download_duration,
-                redirect_duration,
-                response_status: response.status,
-                total_duration: dns_duration
-                    + connect_duration
-                    + tls_duration
-                    + redirect_duration
-                    + sending_duration
-                    + waiting_duration
-                    + download_duration,
-            });
-        }
-
-        if ["301", "303"].contains(&response.status.as_str()) {
-            method = "GET".into();
+        // A pooled connection might have been closed by the server in the meantime.
+        // In that case the request is repeated on a new connection.
+        let pooled = match keep_alive {
+            true => user.connections.remove(&connection_key),
+            false => None,
+        };
+        let reused = match pooled {
+            Some(mut connection) => {
+                connection.limit(read_timeout, deadline);
+                match connection
+                    .request(&http_request, cookies.clone(), is_debug)
+                    .await
+                {
+                    Ok(timings) => Some(Ok((connection, timings))),
+                    Err(e) if e.is_closed_before_response() => None,
+                    // Any other failure would happen on a new connection just as well.
+                    Err(e) => Some(Err(e)),
+                }
+            }
+            None => None,
+        };
+        result.connection_reused = reused.is_some();
+        result.dns_duration = Duration::default();
+        result.connect_duration = Duration::default();
+        result.tls_duration = Duration::default();
+        let attempt = match reused {
+            Some(attempt) => attempt,
+            None => match connect(&http_request, port, resolver, flow, deadline, &mut result).await
+            {
+                Ok(mut connection) => connection
+                    .request(&http_request, cookies, is_debug)
+                    .await
+                    .map(|timings| (connection, timings)),
+                Err(e) => Err(e),
+            },
+        };
+        let (connection, (sending_duration, waiting_duration, download_duration, response)) =
+            match attempt {
+                Ok(attempt) => attempt,
+                Err(error) => return Ok(failed(result, error, start, is_debug)),
+            };
+        result.sending_duration = sending_duration;
+        result.waiting_duration = waiting_duration;
+        result.download_duration = download_duration;
+        if keep_alive && connection.is_reusable(&response) {
+            user.connections.insert(connection_key, connection);
         }
-
-        let location = response
-            .headers
-            .iter()
-            .find(|(k, _)| k == "location")
-            .unwrap_or_else(|| panic!("response of {} did not provide the expected location header but returned the status code {}", &http_request.url, &response.status));
-
         if let Some(domain) = http_request.url.host_str() {
             let cookies = response
                 .headers
@@ -190,27 +704,131 @@ fn execute(
                 .filter(|(key, _)| key == "set-cookie")
                 .map(|(_, v)| v.to_string())
                 .collect();
-            cookiejar.set_all(&cookies, domain.to_string());
+            user.cookiejar.set_all(&cookies, domain.to_string());
         }
 
-        if location.1.starts_with("http") {
-            let url = Url::parse(&location.1).unwrap();
-            http_request = HttpRequest::new(http_version, &method, url);
-        } else {
-            http_request.url.set_path(&location.1)
-        };
-
-        redirect_duration += dns_duration
-            + connect_duration
-            + tls_duration
+        let hop_duration = result.dns_duration
+            + result.connect_duration
+            + result.tls_duration
             + sending_duration
             + waiting_duration
             + download_duration;
+        if redirects == flow.max_redirects
+            || !["301", "302", "303", "307", "308"].contains(&response.status.as_str())
+        {
+            result.total_duration = result.redirect_duration + hop_duration;
+            result.checks = checks::evaluate(&flow.checks, &response, result.total_duration);
+            result.check_failed = result.checks.iter().any(|check| !check.passed);
+            extract::apply(&flow.extract, &response, &mut user.variables);
+            result.response_status = response.status;
+            result.received_bytes = response.received_bytes;
+            return Ok(result);
+        }
+
+        if ["301", "303"].contains(&response.status.as_str()) {
+            method = "GET".into();
+        }
+
+        let Some(location) = response.header("location") else {
+            let error = GarmataError::Redirect {
+                url: http_request.url.clone(),
+                reason: format!("status {} without a location header", response.status),
+            };
+            result.response_status = response.status;
+            return Ok(failed(result, error, start, is_debug));
+        };
+
+        if location.starts_with("http") {
+            let url = match Url::parse(location) {
+                Ok(url) if ["http", "https"].contains(&url.scheme()) && url.has_host() => url,
+                _ => {
+                    let error = GarmataError::Redirect {
+                        url: http_request.url.clone(),
+                        reason: format!("invalid location {location}"),
+                    };
+                    result.response_status = response.status;
+                    return Ok(failed(result, error, start, is_debug));
+                }
+            };
+            http_request = HttpRequest::new(http_version, &method, url).keep_alive(keep_alive);
+        } else {
+            http_request.url.set_path(location)
+        };
+
+        result.redirect_duration += hop_duration;
         redirects += 1;
     }
 }
 
-fn request(
+/// Completes the result of a request that ended without a usable response.
+fn failed(
+    mut result: HttpResult,
+    error: GarmataError,
+    start: Instant,
+    is_debug: bool,
+) -> HttpResult {
+    if is_debug {
+        println!("{error}\n");
+    }
+    result.total_duration = start.elapsed();
+    result.error = Some(Arc::new(error));
+    result
+}
+
+/// Opens a new connection and records the durations of its phases in the result.
+async fn connect(
+    http_request: &HttpRequest,
+    port: u16,
+    resolver: &Resolver,
+    flow: &Flow,
+    deadline: Option<Instant>,
+    result: &mut HttpResult,
+) -> Result<Connection, GarmataError> {
+    let url = &http_request.url;
+    // The connect timeout covers the lookup and the connection, both end at the latest with the request timeout.
+    let connect_deadline = flow
+        .timeouts
+        .connect_timeout
+        .map(|timeout| Instant::now() + Duration::from_millis(timeout))
+        .into_iter()
+        .chain(deadline)
+        .min();
+    let remaining =
+        || connect_deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+    let dns_timeout = GarmataError::Timeout {
+        url: url.clone(),
+        phase: Phase::Dns,
+    };
+    let (addr, dns_duration) = match remaining() {
+        Some(timeout) => future::timeout(timeout, dns_resolve(url, resolver))
+            .await
+            .map_err(|_| dns_timeout)??,
+        None => dns_resolve(url, resolver).await?,
+    };
+    result.dns_duration = dns_duration;
+    let connect_timeout = remaining();
+    let (stream, connect_duration) = tcp_connect(addr, port, url, connect_timeout).await?;
+    result.connect_duration = connect_duration;
+    let limits = Arc::new(Mutex::new(Limits {
+        read_timeout: flow.timeouts.read_timeout.map(Duration::from_millis),
+        deadline,
+    }));
+    let stream = TimedStream {
+        stream,
+        limits: limits.clone(),
+        timer: None,
+    };
+    let (stream, tls_duration, http2) =
+        tls_handshake(stream, url, flow.insecure, http_request.is_http2()).await?;
+    result.tls_duration = tls_duration;
+    let protocol = match http2 {
+        true => Protocol::Http2(Box::new(Http2Connection::handshake(stream, url).await?)),
+        false => Protocol::Http1(stream),
+    };
+    Ok(Connection { protocol, limits })
+}
+
+async fn request(
     stream: &mut Box<dyn ReadAndWrite>,
     http_request: &HttpRequest,
     cookies: Vec<&Cookie>,
@@ -220,144 +838,190 @@ fn request(
     if is_debug {
         println!("{payload}");
     }
+    let io_error = |phase, source| GarmataError::io(&http_request.url, phase, source);
 
     let start = Instant::now();
-    if stream.write_all(payload.as_bytes()).is_err() {
-        return Err(GarmataError {
-            reason: "cannot send request to the server".into(),
-        });
+    if let Err(e) = stream.write_all(payload.as_bytes()).await {
+        return Err(io_error(Phase::Sending, e));
     };
-    if stream.flush().is_err() {
-        return Err(GarmataError {
-            reason: format!(
-                "unexpected I/O errors while sending request to {}",
-                http_request.url
-            ),
-        });
+    if let Err(e) = stream.flush().await {
+        return Err(io_error(Phase::Sending, e));
     };
     let sending_duration = start.elapsed();
 
-    let mut start = Instant::now();
-    let mut waiting_duration = None;
-    let mut payload: Vec<u8> = vec![];
-    loop {
-        let mut chunk = [0u8; 512];
-        match stream.read(&mut chunk) {
-            Ok(size) => {
-                stream.flush().unwrap();
-                if waiting_duration.is_none() {
-                    waiting_duration = Some(start.elapsed());
-                    start = Instant::now();
-                }
-                payload.extend_from_slice(&chunk);
-                if size == 0 || chunk.len() != size {
-                    let download_duration = start.elapsed();
-                    let payload = String::from_utf8_lossy(&payload);
-                    if is_debug {
-                        println!("{payload}");
-                    }
-                    match HttpResponse::try_from(payload) {
-                        Ok(response) => {
-                            return Ok((
-                                sending_duration,
-                                waiting_duration.unwrap(),
-                                download_duration,
-                                response,
-                            ))
-                        }
-                        Err(mut e) => {
-                            e.reason += &format!(" from url {}", http_request.url);
-                            return Err(e);
-                        }
-                    }
-                }
-            }
-            Err(_) => {
-                return Err(GarmataError {
-                    reason: format!(
-                        "could not read server's response for url {}",
-                        http_request.url
-                    ),
-                });
-            }
+    let start = Instant::now();
+    let mut chunk = [0u8; 8192];
+    let size = match stream.read(&mut chunk).await {
+        Ok(0) => {
+            return Err(io_error(
+                Phase::Waiting,
+                io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed without response",
+                ),
+            ))
         }
+        Ok(size) => size,
+        Err(e) => return Err(io_error(Phase::Waiting, e)),
+    };
+    let waiting_duration = start.elapsed();
+
+    let start = Instant::now();
+    let has_body = http_request.method.to_uppercase() != "HEAD";
+    let response =
+        HttpResponse::read(stream, chunk[..size].to_vec(), has_body, &http_request.url).await?;
+    let download_duration = start.elapsed();
+    if is_debug {
+        let headers = response
+            .headers
+            .iter()
+            .map(|(k, v)| format!("{k}: {v}\r\n"))
+            .collect::<String>();
+        println!(
+            "{} {}\r\n{headers}\r\n{}",
+            response.version,
+            response.status,
+            String::from_utf8_lossy(&response.body)
+        );
     }
+    Ok((
+        sending_duration,
+        waiting_duration,
+        download_duration,
+        response,
+    ))
 }
 
-fn tls_handshake(
-    stream: TcpStream,
+/// Wraps the stream in TLS for https urls and returns whether HTTP/2 is spoken on the connection.
+/// HTTP/2 is negotiated with ALPN for https and assumed with prior knowledge for http.
+async fn tls_handshake(
+    stream: TimedStream,
     url: &Url,
     allow_insecure_certificates: bool,
-) -> Result<(Box<dyn ReadAndWrite>, Duration), GarmataError> {
+    http2: bool,
+) -> Result<(Box<dyn ReadAndWrite>, Duration, bool), GarmataError> {
     if url.scheme() == "https" {
-        let tls_connector = TlsConnector::builder()
+        let tls_error = |source: Box<dyn std::error::Error + Send + Sync>| GarmataError::Tls {
+            url: url.clone(),
+            source,
+        };
+        let mut tls_connector = TlsConnector::builder();
+        tls_connector
             .danger_accept_invalid_hostnames(allow_insecure_certificates)
-            .danger_accept_invalid_certs(allow_insecure_certificates)
-            .build()
-            .unwrap();
-        let domain = url.host_str().unwrap();
+            .danger_accept_invalid_certs(allow_insecure_certificates);
+        if http2 {
+            tls_connector.request_alpns(&["h2", "http/1.1"]);
+        }
+        let tls_connector = tls_connector.build().map_err(|e| tls_error(e.into()))?;
+        let domain = url.host_str().unwrap_or_default();
         let start = Instant::now();
-        match tls_connector.connect(domain, stream) {
-            Ok(mut stream) => match stream.flush() {
-                Ok(_) => Ok((Box::new(stream), start.elapsed())),
-                Err(_) => Err(GarmataError {
-                    reason: format!("unexpected I/O errors while tls handshake to {domain}"),
-                }),
-            },
-            Err(_) => Err(GarmataError {
-                reason: format!("cannot establish a tls handshake to {domain}"),
+        match tls::connect(&tls_connector, domain, stream).await {
+            Ok(stream) => {
+                let http2 = stream.negotiated_alpn().as_deref() == Some(b"h2".as_slice());
+                Ok((Box::new(stream), start.elapsed(), http2))
+            }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => Err(GarmataError::Timeout {
+                url: url.clone(),
+                phase: Phase::Tls,
             }),
+            Err(e) => Err(tls_error(e.into())),
         }
     } else {
-        Ok((Box::new(stream), Duration::default()))
+        Ok((Box::new(stream), Duration::default(), http2))
     }
 }
 
-fn tcp_connect(addr: IpAddr, port: u16) -> Result<(TcpStream, Duration), GarmataError> {
+async fn tcp_connect(
+    addr: IpAddr,
+    port: u16,
+    url: &Url,
+    timeout: Option<Duration>,
+) -> Result<(TcpStream, Duration), GarmataError> {
     let start = Instant::now();
-    match TcpStream::connect((addr, port)) {
-        Ok(mut stream) => match stream.flush() {
-            Ok(_) => Ok((stream, start.elapsed())),
-            Err(_) => Err(GarmataError {
-                reason: format!("unexpected I/O errors while connection to {addr}:{port}"),
-            }),
-        },
-        Err(_) => Err(GarmataError {
-            reason: format!("cannot connect to {addr}:{port}"),
+    let stream = match timeout {
+        Some(timeout) if timeout.is_zero() => Err(io::ErrorKind::TimedOut.into()),
+        Some(timeout) => io::timeout(timeout, TcpStream::connect((addr, port))).await,
+        None => TcpStream::connect((addr, port)).await,
+    };
+    // Small HTTP/2 frames must not wait for the acknowledgement of previous segments.
+    let stream = stream.and_then(|stream| stream.set_nodelay(true).map(|_| stream));
+    match stream {
+        Ok(stream) => Ok((stream, start.elapsed())),
+        Err(source) if source.kind() == io::ErrorKind::TimedOut => Err(GarmataError::Timeout {
+            url: url.clone(),
+            phase: Phase::Connect,
+        }),
+        Err(source) => Err(GarmataError::Connect {
+            url: url.clone(),
+            source,
         }),
     }
 }
 
-fn dns_resolve(url: &Url) -> Result<(IpAddr, Duration), GarmataError> {
+async fn dns_resolve(url: &Url, resolver: &Resolver) -> Result<(IpAddr, Duration), GarmataError> {
+    let dns_error = |source: Box<dyn std::error::Error + Send + Sync>| GarmataError::Dns {
+        url: url.clone(),
+        source,
+    };
+    let host = url.host_str().unwrap_or_default();
     if url.domain().is_none() {
-        let mut ip_str = url.host_str().unwrap();
-        if ip_str.starts_with('[') {
-            ip_str = &ip_str[1..ip_str.len() - 1];
-        }
+        let ip_str = host.trim_start_matches('[').trim_end_matches(']');
         match IpAddr::from_str(ip_str) {
             Ok(addr) => Ok((addr, Duration::default())),
-            Err(e) => Err(GarmataError {
-                reason: e.to_string(),
-            }),
+            Err(e) => Err(dns_error(e.into())),
         }
     } else {
-        let resolver = DnsResolver::from_system_conf().unwrap();
+        let resolver = resolver.get().await.map_err(|e| dns_error(e.into()))?;
         let start = Instant::now();
-        let result = resolver.lookup_ip(url.host_str().unwrap());
+        let result = resolver.lookup_ip(host).await;
         let duration = start.elapsed();
         match result {
             Ok(response) => {
                 if let Some(addr) = response.iter().find(|i| i.is_ipv4() || i.is_ipv6()) {
                     Ok((addr, duration))
                 } else {
-                    Err(GarmataError {
-                        reason: "unresolved hostname".into(),
-                    })
+                    Err(dns_error("unresolved hostname".into()))
                 }
             }
-            Err(e) => Err(GarmataError {
-                reason: e.to_string(),
-            }),
+            Err(e) => Err(dns_error(e.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stage(name: &str, duration: u64, users: usize) -> Stage {
+        Stage {
+            name: name.into(),
+            duration,
+            users,
         }
     }
+
+    #[test]
+    fn stages_ramp_linearly() {
+        let stages = [
+            stage("ramp", 10, 10),
+            stage("", 20, 10),
+            stage("down", 10, 0),
+        ];
+        let at = |seconds| stage_at(&stages, Duration::from_secs_f64(seconds));
+        assert_eq!(at(0.0), ("ramp".into(), 0));
+        assert_eq!(at(2.5), ("ramp".into(), 3));
+        assert_eq!(at(10.0), ("2".into(), 10));
+        assert_eq!(at(29.9), ("2".into(), 10));
+        assert_eq!(at(35.0), ("down".into(), 5));
+    }
+
+    #[test]
+    fn last_stage_holds_its_target() {
+        let stages = [stage("ramp", 10, 4)];
+        assert_eq!(
+            stage_at(&stages, Duration::from_secs(60)),
+            ("ramp".into(), 4)
+        );
+        assert_eq!(stage_at(&[], Duration::from_secs(1)), (String::new(), 0));
+    }
 }
@@ -1,119 +1,384 @@
 // MIT License - free as in freedom; Full license in the LICENSE file
-use async_std::task::block_on;
-use clap::{Parser, ValueEnum};
-use garmata::http::HttpResult;
-use std::{collections::HashMap, time::Duration};
+use async_std::{net::TcpListener, task::block_on};
+use clap::{Parser, Subcommand, ValueEnum};
+use garmata::{
+    compare::Comparison,
+    configuration::Configuration,
+    metrics::Metrics,
+    progress::Progress,
+    report::{latency_lines, verdict_value},
+    sinks::{CsvSink, NdjsonSink, ResultSink},
+    summary::FlowSummary,
+    thresholds::Verdict,
+    Report, RunState,
+};
+use std::{
+    fs::File,
+    io::{stderr, stdout, IsTerminal, Write},
+    process::exit,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
 
 #[derive(Clone, ValueEnum, PartialEq, Eq)]
 enum Output {
     Stats,
     Csv,
     Debug,
+    /// The aggregated summary as one json document
+    Json,
+    /// One json object per request as it completes
+    Ndjson,
+    /// JUnit XML with a testsuite per group and a testcase per flow, check and threshold
+    Junit,
+    /// A self-contained html report with charts and the configuration
+    Html,
 }
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Location of the test configuration file [default: ./configuration.yaml]
     configuration: Option<String>,
 
     #[arg(short, long, value_enum)]
     /// Output format [default: stats]
     output: Option<Output>,
+
+    #[arg(long)]
+    /// Write the time series of all flows as csv to this file, requires an interval in the configuration
+    time_series: Option<String>,
+
+    #[arg(long)]
+    /// Write a self-contained html report to this file
+    report: Option<String>,
+
+    #[arg(long, value_name = "ADDR:PORT")]
+    /// Serve Prometheus metrics of the running test on this address at /metrics
+    metrics_listen: Option<String>,
+
+    #[arg(long)]
+    /// Do not show the progress of the run on stderr
+    no_progress: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Compare the json summaries of two runs of the same configuration
+    Compare {
+        /// Summary written with --output json before the change
+        baseline: String,
+        /// Summary written with --output json after the change
+        current: String,
+
+        #[arg(long, default_value_t = 10.0)]
+        /// Percent latencies may grow and throughput may shrink before it is a regression
+        tolerance: f64,
+
+        #[arg(long, default_value_t = 1.0)]
+        /// Percentage points the error rate may grow before it is a regression
+        error_tolerance: f64,
+    },
 }
 
+/// Exit code of a run that breached at least one threshold.
+const THRESHOLDS_BREACHED: i32 = 99;
+/// Exit code of a comparison that found a regression, like breached thresholds fail a run.
+const REGRESSION: i32 = 99;
+/// Exit code of a run stopped by SIGINT or SIGTERM, as shells report an interrupted program.
+const INTERRUPTED: i32 = 130;
+
 fn main() {
     let cli = Cli::parse();
+    if let Some(Command::Compare {
+        baseline,
+        current,
+        tolerance,
+        error_tolerance,
+    }) = &cli.command
+    {
+        let runs = garmata::compare::load(baseline)
+            .and_then(|baseline| Ok((baseline, garmata::compare::load(current)?)));
+        let (baseline, current) = match runs {
+            Ok(runs) => runs,
+            Err(e) => {
+                eprintln!("{e}");
+                exit(1);
+            }
+        };
+        let comparisons =
+            garmata::compare::compare(&baseline, &current, *tolerance, *error_tolerance);
+        if summary_comparisons(&comparisons) {
+            exit(REGRESSION);
+        }
+        return;
+    }
     let config = cli.configuration.unwrap_or("configuration.yaml".into());
-    let is_debug = cli.output == Some(Output::Debug);
-    match block_on(garmata::run(config, is_debug)) {
-        Ok(results) => match cli.output.unwrap_or(Output::Stats) {
-            Output::Csv => summary_csv(&results),
-            Output::Stats => summary_stats(&results),
-            Output::Debug => {},
+    let output = cli.output.unwrap_or(Output::Stats);
+    // The html report shows the configuration as written, including its comments.
+    let source = std::fs::read_to_string(&config).unwrap_or_default();
+    let config = match Configuration::load(&config) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            exit(1);
+        }
+    };
+    if cli.time_series.is_some() && config.interval.is_none() {
+        eprintln!("--time-series requires an interval in the configuration");
+        exit(1);
+    }
+    let thresholds = config.thresholds.clone();
+    let mut sinks: Vec<Box<dyn ResultSink>> = vec![];
+    // Stdout is line buffered, so every row reaches a consumer of the stream as soon as its request finished.
+    match output {
+        Output::Csv => match CsvSink::new(stdout()) {
+            Ok(sink) => sinks.push(Box::new(sink)),
+            Err(e) => {
+                eprintln!("{e}");
+                exit(1);
+            }
         },
-        Err(e) => eprintln!("{}", e.reason),
+        Output::Ndjson => sinks.push(Box::new(NdjsonSink::new(stdout()))),
+        _ => {}
+    }
+    let state = Arc::new(RunState::default());
+    if let Some(address) = &cli.metrics_listen {
+        let listener = match block_on(TcpListener::bind(address)) {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("cannot listen on {address}: {e}");
+                exit(1);
+            }
+        };
+        let metrics = Metrics::new(state.clone());
+        sinks.push(Box::new(metrics.clone()));
+        async_std::task::spawn(metrics.serve(listener));
+    }
+    // Outputs streamed to the same terminal would tear the display apart.
+    let streaming = matches!(output, Output::Csv | Output::Ndjson | Output::Debug);
+    let show_progress =
+        !cli.no_progress && stderr().is_terminal() && !(streaming && stdout().is_terminal());
+    if show_progress {
+        let progress = Progress::new(&config, state.clone());
+        sinks.push(Box::new(progress.clone()));
+        async_std::task::spawn(progress.show());
+    }
+    // The first signal stops the run gracefully and keeps its results, the second one aborts.
+    let grace_period = config.grace_period;
+    let signal_state = state.clone();
+    let handler = ctrlc::set_handler(move || {
+        if signal_state.interrupted.swap(true, Ordering::SeqCst) {
+            exit(INTERRUPTED);
+        }
+        signal_state.stop.store(true, Ordering::SeqCst);
+        // The progress display shows the stop itself.
+        if !show_progress {
+            eprintln!("stopping, waiting up to {grace_period}s for requests in flight, interrupt again to abort");
+        }
+    });
+    if let Err(e) = handler {
+        eprintln!("cannot handle interrupts: {e}");
+    }
+    let run = garmata::run(config, output == Output::Debug, sinks, state.clone());
+    let report = match block_on(run) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("{e}");
+            exit(1);
+        }
     };
+    let verdicts = garmata::thresholds::evaluate(&thresholds, &report);
+    match output {
+        Output::Stats => summary_stats(&report),
+        Output::Json => {
+            match serde_json::to_string_pretty(&garmata::report::json(&report, &verdicts)) {
+                Ok(json) => println!("{json}"),
+                Err(e) => eprintln!("{e}"),
+            }
+        }
+        Output::Junit => print!("{}", garmata::report::junit(&report, &verdicts)),
+        Output::Html => print!("{}", garmata::report::html(&report, &verdicts, &source)),
+        _ => {}
+    }
+    if let Some(path) = &cli.report {
+        if let Err(e) = std::fs::write(path, garmata::report::html(&report, &verdicts, &source)) {
+            eprintln!("cannot write the report to {path}: {e}");
+            exit(1);
+        }
+    }
+    if let Some(path) = &cli.time_series {
+        if let Err(e) =
+            File::create(path).and_then(|file| garmata::report::time_series_csv(file, &report))
+        {
+            eprintln!("cannot write the time series to {path}: {e}");
+            exit(1);
+        }
+    }
+    summary_thresholds(&verdicts, output == Output::Stats);
+    if state.interrupted.load(Ordering::SeqCst) {
+        exit(INTERRUPTED);
+    }
+    if verdicts.iter().any(|verdict| !verdict.passed) {
+        exit(THRESHOLDS_BREACHED);
+    }
 }
 
-fn summary_csv(results: &Vec<HttpResult>) {
-    println!("start timestamp,response status,group,flow,total in μs,DNS lookup in μs,Connection in μs,TLS handshake in μs,redirecting in μs,sending in μs,waiting in μs,downloading in μs");
-    for r in results {
+/// Prints the deltas of every flow and returns whether any regressed.
+fn summary_comparisons(comparisons: &[Comparison]) -> bool {
+    let mut group = None;
+    let mut regressions = 0;
+    for comparison in comparisons {
+        if group != Some(&comparison.group) {
+            println!("Group: {}", comparison.group);
+            group = Some(&comparison.group);
+        }
+        match comparison.stage.is_empty() {
+            true => println!("  Flow: {}", comparison.flow),
+            false => println!("  Flow: {} (stage {})", comparison.flow, comparison.stage),
+        }
+        if let Some(run) = comparison.missing_in {
+            println!("    missing in the {run} run");
+            continue;
+        }
         println!(
-            "{},{},{},{},{},{},{},{},{},{},{},{}",
-            r.start_timestamp,
-            r.response_status,
-            r.group,
-            r.flow,
-            r.total_duration.as_micros(),
-            r.dns_duration.as_micros(),
-            r.connect_duration.as_micros(),
-            r.tls_duration.as_micros(),
-            r.redirect_duration.as_micros(),
-            r.sending_duration.as_micros(),
-            r.waiting_duration.as_micros(),
-            r.download_duration.as_micros(),
+            "    {:<12} {:>14} {:>14} {:>10}",
+            "metric", "baseline", "current", "change"
         );
+        for delta in &comparison.deltas {
+            let change = match delta.unit {
+                "%" => format!("{:+.2}pp", delta.change),
+                _ => format!("{:+.2}%", delta.change),
+            };
+            let mut line = format!(
+                "    {:<12} {:>14} {:>14} {change:>10}",
+                delta.metric,
+                format!("{:.3}{}", delta.baseline, delta.unit),
+                format!("{:.3}{}", delta.current, delta.unit),
+            );
+            if let Some(p_value) = delta.p_value {
+                line += &format!(" (p={p_value:.3})");
+            }
+            if delta.regression {
+                line += " REGRESSION";
+                regressions += 1;
+            }
+            println!("{line}");
+        }
+    }
+    match regressions {
+        0 => println!("No regressions"),
+        1 => println!("1 regression"),
+        _ => println!("{regressions} regressions"),
     }
+    regressions > 0
 }
 
-fn summary_stats(results: &Vec<HttpResult>) {
-    let mut formatted: HashMap<String, HashMap<String, Vec<Duration>>> = HashMap::new();
-    for r in results {
-        match formatted.get_mut(&r.group) {
-            Some(flow_map) => {
-                match flow_map.get_mut(&r.flow) {
-                    Some(v) => {
-                        if let Some(index) = v.iter().position(|&x| x >= r.total_duration) {
-                            v.splice(index..index, vec![r.total_duration]);
-                        } else {
-                            v.push(r.total_duration);
-                        }
-                    }
-                    None => {
-                        flow_map.insert(r.flow.clone(), vec![r.total_duration]);
-                    }
-                };
-            }
-            None => {
-                let mut map = HashMap::new();
-                map.insert(r.flow.clone(), vec![r.total_duration]);
-                formatted.insert(r.group.clone(), map);
-            }
+/// Verdicts go to stdout next to the stats and to stderr to keep other outputs parsable.
+fn summary_thresholds(verdicts: &[Verdict], to_stdout: bool) {
+    if verdicts.is_empty() {
+        return;
+    }
+    let mut out: Box<dyn Write> = match to_stdout {
+        true => Box::new(stdout()),
+        false => Box::new(stderr()),
+    };
+    let _ = writeln!(out, "Thresholds:");
+    for verdict in verdicts {
+        let result = match verdict.passed {
+            true => "passed",
+            false => "BREACHED",
         };
+        let value = verdict_value(verdict);
+        let _ = writeln!(
+            out,
+            "  {:.<70} {result} ({value})",
+            format!("{}: ", verdict.description)
+        );
     }
-    for (group, map) in &formatted {
-        let total = &map.iter().map(|(_, timings)| timings.len()).sum::<usize>();
+}
+
+fn summary_stats(report: &Report) {
+    let summary = &report.summary;
+    for group in summary.groups() {
+        let flows: Vec<_> = summary
+            .flows
+            .iter()
+            .filter(|flow| flow.group == group)
+            .collect();
+        let total = flows.iter().map(|flow| flow.requests).sum::<usize>();
         let pad = if group.is_empty() { "" } else { " " };
-        println!("Group: {group}{pad}({total} requests total)");
-        for (flow, durations) in map {
-            println!("  Flow: {flow}");
-            println!(
-                "    {:.<68} {}s",
-                "min: ",
-                durations.first().unwrap().as_secs_f32()
-            );
-            println!(
-                "    {:.<68} {}s",
-                "avg: ",
-                durations.iter().map(|d| d.as_secs_f32()).sum::<f32>() / (durations.len() as f32),
-            );
-            println!(
-                "    {:.<68} {}s",
-                "p50: ",
-                durations[durations.len() / 2].as_secs_f32()
-            );
+        match report.dropped_iterations.get(group) {
+            Some(&dropped) if dropped > 0 => println!(
+                "Group: {group}{pad}({total} requests total, {dropped} iterations dropped)"
+            ),
+            _ => println!("Group: {group}{pad}({total} requests total)"),
+        }
+        for flow in flows {
+            if flow.stage.is_empty() {
+                println!("  Flow: {}", flow.flow);
+            } else {
+                println!("  Flow: {} (stage {})", flow.flow, flow.stage);
+            }
+            let failed = flow.failed();
             println!(
-                "    {:.<68} {}s",
-                "p95: ",
-                durations[((durations.len() as f32) * 0.95).floor() as usize].as_secs_f32()
+                "    {:.<68} {failed} of {} ({:.2}%)",
+                "errors: ",
+                flow.requests,
+                failed as f64 / flow.requests as f64 * 100.0
             );
+            for (kind, count) in &flow.errors {
+                println!("    {:.<68} {count}", format!("{kind} errors: "));
+            }
+            println!("    {:.<68} {:.2} rps", "throughput: ", flow.throughput());
             println!(
-                "    {:.<68} {}s",
-                "max: ",
-                durations.last().unwrap().as_secs_f32()
+                "    {:.<68} {:.0} bytes/s",
+                "received: ",
+                flow.received_per_second()
             );
+            for line in latency_lines(flow, &summary.percentiles) {
+                println!("    {line}");
+            }
+            for (name, passed, failed) in &flow.checks {
+                println!(
+                    "    {:.<68} {passed} passed, {failed} failed",
+                    format!("check {name}: ")
+                );
+            }
+            if let Some(interval) = summary.interval {
+                summary_time_series(flow, &summary.percentiles, interval);
+            }
+        }
+    }
+}
+
+/// One row per interval with the latencies in milliseconds.
+fn summary_time_series(flow: &FlowSummary, percentiles: &[f64], interval: Duration) {
+    println!("    time series every {}s:", interval.as_secs());
+    let mut header = format!("{:>10} {:>10} {:>8}", "start", "requests", "errors");
+    for percentile in percentiles {
+        header += &format!(" {:>10}", format!("p{percentile} ms"));
+    }
+    println!("    {header}");
+    for entry in &flow.intervals {
+        let mut row = format!(
+            "{:>10} {:>10} {:>8}",
+            format!("{}s", entry.start.as_secs()),
+            entry.requests,
+            entry.errors
+        );
+        for (_, latency) in &entry.latencies {
+            row += &format!(" {:>10.3}", *latency as f64 / 1000.0);
+        }
+        // Intervals without responses have no latencies.
+        if entry.latencies.is_empty() {
+            row += &format!(" {:>10}", "-").repeat(percentiles.len());
         }
+        println!("    {row}");
     }
 }
@@ -0,0 +1,198 @@
+use crate::{http::HttpResult, sinks::ResultSink, RunState};
+use async_std::{
+    io::{self, ReadExt, WriteExt},
+    net::{TcpListener, TcpStream},
+    task::spawn,
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::Duration,
+};
+
+/// Largest request head a scrape may send before its connection is closed.
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// Upper bounds in seconds of the buckets of the duration histograms.
+const BUCKETS: [f64; 13] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Metrics of a running test in the Prometheus text format, fed as a sink and served over HTTP.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Arc<Mutex<Registry>>,
+    state: Arc<RunState>,
+}
+
+#[derive(Default)]
+struct Registry {
+    /// Counter values by metric name and labels.
+    counters: BTreeMap<(&'static str, String), u64>,
+    /// Durations by labels.
+    durations: BTreeMap<String, Buckets>,
+}
+
+#[derive(Default)]
+struct Buckets {
+    /// Observations per bucket and above the last one, not cumulative.
+    counts: [u64; BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+impl Metrics {
+    pub fn new(state: Arc<RunState>) -> Self {
+        Self {
+            registry: Default::default(),
+            state,
+        }
+    }
+
+    /// Answers every request to `/metrics` with the current metrics until the program ends.
+    pub async fn serve(self, listener: TcpListener) {
+        while let Ok((stream, _)) = listener.accept().await {
+            let metrics = self.clone();
+            spawn(async move {
+                if let Err(e) = metrics.respond(stream).await {
+                    eprintln!("cannot serve metrics: {e}");
+                }
+            });
+        }
+    }
+
+    async fn respond(&self, mut stream: TcpStream) -> io::Result<()> {
+        let mut request = vec![];
+        let mut buffer = [0; 1024];
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+            let read = stream.read(&mut buffer).await?;
+            if read == 0 {
+                return Ok(());
+            }
+            request.extend_from_slice(&buffer[..read]);
+            if request.len() > MAX_REQUEST_HEAD {
+                return Ok(());
+            }
+        }
+        let request = String::from_utf8_lossy(&request);
+        let (status, body) = match request.split(' ').nth(1) {
+            Some("/metrics") => ("200 OK", self.render()),
+            _ => ("404 Not Found", "not found\n".to_string()),
+        };
+        let response = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(response.as_bytes()).await?;
+        stream.flush().await
+    }
+
+    fn render(&self) -> String {
+        let registry = self.registry.lock().unwrap();
+        let mut out = String::new();
+        let mut previous = "";
+        for ((name, labels), value) in &registry.counters {
+            if *name != previous {
+                let help = match *name {
+                    "garmata_requests_total" => {
+                        "Finished requests by response status, none for requests without a response."
+                    }
+                    "garmata_errors_total" => {
+                        "Failed requests by error category or status for responses with an error status."
+                    }
+                    _ => "Bytes received in responses.",
+                };
+                let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter");
+                previous = name;
+            }
+            let _ = writeln!(out, "{name}{{{labels}}} {value}");
+        }
+        let name = "garmata_request_duration_seconds";
+        let _ = writeln!(out, "# HELP {name} Durations of the requests with a response and of their phases.\n# TYPE {name} histogram");
+        for (labels, buckets) in &registry.durations {
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(&buckets.counts) {
+                cumulative += count;
+                let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{bound}\"}} {cumulative}");
+            }
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{labels},le=\"+Inf\"}} {}\n{name}_sum{{{labels}}} {}\n{name}_count{{{labels}}} {}",
+                buckets.count, buckets.sum, buckets.count
+            );
+        }
+        let name = "garmata_active_users";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Virtual users in the middle of an iteration.\n# TYPE {name} gauge\n{name} {}",
+            self.state.active_users.load(Ordering::SeqCst)
+        );
+        out
+    }
+}
+
+impl ResultSink for Metrics {
+    fn write(&mut self, result: &HttpResult) -> std::io::Result<()> {
+        let mut registry = self.registry.lock().unwrap();
+        let flow = format!(
+            "group=\"{}\",flow=\"{}\"",
+            escape(&result.group),
+            escape(&result.flow)
+        );
+        let status = match result.response_status.is_empty() {
+            true => "none",
+            false => &result.response_status,
+        };
+        let labels = format!("{flow},status=\"{}\"", escape(status));
+        *registry
+            .counters
+            .entry(("garmata_requests_total", labels))
+            .or_default() += 1;
+        if let Some(kind) = result.error_kind() {
+            let labels = format!("{flow},kind=\"{kind}\"");
+            *registry
+                .counters
+                .entry(("garmata_errors_total", labels))
+                .or_default() += 1;
+        }
+        *registry
+            .counters
+            .entry(("garmata_received_bytes_total", flow.clone()))
+            .or_default() += result.received_bytes as u64;
+        if result.error.is_none() {
+            let phases = result
+                .phase_durations()
+                .map(|(phase, duration)| (phase.name(), duration));
+            for (phase, duration) in [("total", result.total_duration)].into_iter().chain(phases) {
+                let labels = format!("{flow},phase=\"{phase}\"");
+                registry
+                    .durations
+                    .entry(labels)
+                    .or_default()
+                    .observe(duration);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Buckets {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
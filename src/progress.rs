@@ -0,0 +1,201 @@
+use crate::{configuration::Configuration, http::HttpResult, sinks::ResultSink, RunState};
+use async_std::task::sleep;
+use hdrhistogram::Histogram;
+use std::{
+    collections::VecDeque,
+    io::{self, stderr, Write},
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Seconds the rates and percentiles of every flow look back.
+const WINDOW: usize = 5;
+
+/// Live display of a running test on a terminal, redrawn every second and removed once the run ends.
+#[derive(Clone)]
+pub struct Progress {
+    display: Arc<Mutex<Display>>,
+    state: Arc<RunState>,
+}
+
+struct Display {
+    start: Instant,
+    /// Name and planned duration of every group.
+    groups: Vec<(String, Duration)>,
+    /// Group, flow and results of the last seconds, the current second first.
+    flows: Vec<(String, String, VecDeque<Second>)>,
+    /// Lines of the last drawing, overwritten by the next one.
+    drawn: usize,
+    done: bool,
+}
+
+struct Second {
+    requests: usize,
+    errors: usize,
+    /// Total durations in microseconds of the requests that received a response.
+    latencies: Histogram<u64>,
+}
+
+impl Progress {
+    pub fn new(config: &Configuration, state: Arc<RunState>) -> Self {
+        let groups = config
+            .groups
+            .iter()
+            .map(|group| {
+                let duration = match group.stages.is_empty() {
+                    true => group.duration,
+                    false => group.stages.iter().map(|stage| stage.duration).sum(),
+                };
+                (group.name.clone(), Duration::from_secs(duration))
+            })
+            .collect();
+        let display = Display {
+            start: Instant::now(),
+            groups,
+            flows: vec![],
+            drawn: 0,
+            done: false,
+        };
+        Self {
+            display: Arc::new(Mutex::new(display)),
+            state,
+        }
+    }
+
+    /// Redraws the display on stderr every second until the run ends.
+    pub async fn show(self) {
+        loop {
+            sleep(Duration::from_secs(1)).await;
+            let mut display = self.display.lock().unwrap();
+            if display.done {
+                return;
+            }
+            let lines = display.lines(&self.state);
+            display.draw(&lines);
+            // The oldest second leaves the window, a new one starts.
+            for (.., seconds) in &mut display.flows {
+                seconds.truncate(WINDOW - 1);
+                seconds.push_front(Second::new());
+            }
+        }
+    }
+}
+
+impl ResultSink for Progress {
+    fn write(&mut self, result: &HttpResult) -> io::Result<()> {
+        let mut display = self.display.lock().unwrap();
+        let index = match display
+            .flows
+            .iter()
+            .position(|(group, flow, _)| *group == result.group && *flow == result.flow)
+        {
+            Some(index) => index,
+            None => {
+                let seconds = VecDeque::from([Second::new()]);
+                display
+                    .flows
+                    .push((result.group.clone(), result.flow.clone(), seconds));
+                display.flows.len() - 1
+            }
+        };
+        let second = &mut display.flows[index].2[0];
+        second.requests += 1;
+        if result.is_error() {
+            second.errors += 1;
+        }
+        // The histogram grows with the values, so recording cannot fail.
+        if result.error.is_none() {
+            let _ = second
+                .latencies
+                .record(result.total_duration.as_micros() as u64);
+        }
+        Ok(())
+    }
+
+    /// Removes the display, so the summary after the run starts on a clean terminal.
+    fn finish(&mut self) -> io::Result<()> {
+        let mut display = self.display.lock().unwrap();
+        display.done = true;
+        display.draw(&[]);
+        Ok(())
+    }
+}
+
+impl Display {
+    fn lines(&self, state: &RunState) -> Vec<String> {
+        // Whole seconds, so elapsed and left time add up to the duration.
+        let elapsed = Duration::from_secs(self.start.elapsed().as_secs());
+        let mut lines = vec![format!(
+            "garmata {} elapsed, {} active users",
+            clock(elapsed),
+            state.active_users.load(Ordering::SeqCst)
+        )];
+        if state.stop.load(Ordering::SeqCst) {
+            lines[0] += ", stopping";
+        }
+        for (group, duration) in &self.groups {
+            lines.push(format!(
+                "{group}: {} of {} ({} left)",
+                clock(elapsed.min(*duration)),
+                clock(*duration),
+                clock(duration.saturating_sub(elapsed))
+            ));
+            for (_, flow, seconds) in self.flows.iter().filter(|(other, ..)| other == group) {
+                let requests = seconds.iter().map(|second| second.requests).sum::<usize>();
+                let errors = seconds.iter().map(|second| second.errors).sum::<usize>();
+                let mut line = format!(
+                    "  {flow}: {:.1} rps, {:.2}% errors",
+                    requests as f64 / seconds.len() as f64,
+                    match requests {
+                        0 => 0.0,
+                        _ => errors as f64 / requests as f64 * 100.0,
+                    }
+                );
+                let mut latencies = Histogram::<u64>::new(3).unwrap();
+                for second in seconds {
+                    let _ = latencies.add(&second.latencies);
+                }
+                if !latencies.is_empty() {
+                    let millis = |quantile| latencies.value_at_quantile(quantile) as f64 / 1000.0;
+                    line += &format!(", p50 {:.1}ms, p95 {:.1}ms", millis(0.5), millis(0.95));
+                }
+                lines.push(line);
+            }
+        }
+        lines
+    }
+
+    /// Replaces the last drawing with the lines.
+    fn draw(&mut self, lines: &[String]) {
+        let mut out = String::new();
+        if self.drawn > 0 {
+            out += &format!("\r\x1b[{}A", self.drawn);
+        }
+        out += "\x1b[J";
+        for line in lines {
+            out += line;
+            out += "\n";
+        }
+        let _ = stderr().write_all(out.as_bytes());
+        self.drawn = lines.len();
+    }
+}
+
+impl Second {
+    fn new() -> Self {
+        Self {
+            requests: 0,
+            errors: 0,
+            latencies: Histogram::new(3).unwrap(),
+        }
+    }
+}
+
+/// Minutes and seconds, with hours in front for long runs.
+fn clock(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds / 3600 {
+        0 => format!("{:02}:{:02}", seconds / 60, seconds % 60),
+        hours => format!("{hours}:{:02}:{:02}", seconds / 60 % 60, seconds % 60),
+    }
+}
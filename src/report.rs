@@ -0,0 +1,608 @@
+use crate::{summary::FlowSummary, thresholds::Verdict, Report};
+use hdrhistogram::Histogram;
+use serde_json::{json, Map, Value};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    time::Duration,
+};
+
+/// Value of a verdict with its unit.
+pub fn verdict_value(verdict: &Verdict) -> String {
+    match verdict.value {
+        Some(value) => format!("{value:.2}{}", verdict.unit),
+        None => "no results".into(),
+    }
+}
+
+/// The summary of all groups and flows and the threshold verdicts with latencies in milliseconds.
+pub fn json(report: &Report, verdicts: &[Verdict]) -> Value {
+    let summary = &report.summary;
+    let duration = report.duration.as_secs_f64();
+    let millis = |micros: u64| micros as f64 / 1000.0;
+    let groups: Vec<_> = summary
+        .groups()
+        .into_iter()
+        .map(|group| {
+            let flows: Vec<_> = summary
+                .flows
+                .iter()
+                .filter(|flow| flow.group == group)
+                .collect();
+            let requests = flows.iter().map(|flow| flow.requests).sum::<usize>();
+            let flows: Vec<_> = flows
+                .into_iter()
+                .map(|flow| {
+                    let phases: Map<String, Value> = flow
+                        .phases
+                        .iter()
+                        .map(|(phase, durations)| {
+                            (
+                                phase.name().to_string(),
+                                latency_json(durations, &summary.percentiles),
+                            )
+                        })
+                        .collect();
+                    let intervals: Vec<_> = flow
+                        .intervals
+                        .iter()
+                        .map(|entry| {
+                            let latencies: Map<String, Value> = entry
+                                .latencies
+                                .iter()
+                                .map(|(percentile, latency)| {
+                                    (format!("p{percentile}"), json!(millis(*latency)))
+                                })
+                                .collect();
+                            json!({
+                                "start_s": entry.start.as_secs(),
+                                "requests": entry.requests,
+                                "errors": entry.errors,
+                                "received_bytes": entry.received_bytes,
+                                "latency_ms": latencies,
+                            })
+                        })
+                        .collect();
+                    let errors: Map<String, Value> = flow
+                        .errors
+                        .iter()
+                        .map(|(kind, count)| (kind.clone(), json!(count)))
+                        .collect();
+                    let checks: Vec<_> = flow
+                        .checks
+                        .iter()
+                        .map(|(name, passed, failed)| {
+                            json!({"name": name, "passed": passed, "failed": failed})
+                        })
+                        .collect();
+                    json!({
+                        "name": flow.flow,
+                        "stage": flow.stage,
+                        "requests": flow.requests,
+                        "errors": flow.failed(),
+                        "error_rate": flow.failed() as f64 / flow.requests as f64 * 100.0,
+                        "errors_by_kind": errors,
+                        "check_failures": flow.check_failures,
+                        "checks": checks,
+                        "duration_s": flow.duration().as_secs_f64(),
+                        "throughput_rps": flow.throughput(),
+                        "received_bytes_per_s": flow.received_per_second(),
+                        "latency_ms": latency_json(&flow.latencies, &summary.percentiles),
+                        "phases_ms": phases,
+                        "time_series": intervals,
+                    })
+                })
+                .collect();
+            json!({
+                "name": group,
+                "requests": requests,
+                "dropped_iterations": report.dropped_iterations.get(group).copied().unwrap_or_default(),
+                "flows": flows,
+            })
+        })
+        .collect();
+    let thresholds: Vec<_> = verdicts
+        .iter()
+        .map(|verdict| {
+            json!({
+                "description": verdict.description,
+                "value": verdict.value,
+                "unit": verdict.unit.trim(),
+                "passed": verdict.passed,
+            })
+        })
+        .collect();
+    json!({
+        "duration_s": duration,
+        "interval_s": summary.interval.map(|interval| interval.as_secs()),
+        "groups": groups,
+        "thresholds": thresholds,
+    })
+}
+
+/// Every group is a testsuite with a testcase per flow and check, the thresholds are a testsuite of their own.
+/// Flows fail with errors or failed checks and carry their latencies in the system-out.
+pub fn junit(report: &Report, verdicts: &[Verdict]) -> String {
+    let summary = &report.summary;
+    let duration = report.duration.as_secs_f64();
+    let mut suites = vec![];
+    for group in summary.groups() {
+        let mut cases = vec![];
+        for flow in summary.flows.iter().filter(|flow| flow.group == group) {
+            let name = match flow.stage.is_empty() {
+                true => flow.flow.clone(),
+                false => format!("{} (stage {})", flow.flow, flow.stage),
+            };
+            let mut reasons = vec![];
+            if flow.failed() > 0 {
+                let kinds: Vec<_> = flow
+                    .errors
+                    .iter()
+                    .map(|(kind, count)| format!("{kind} errors: {count}"))
+                    .collect();
+                reasons.push(format!(
+                    "{} of {} requests failed ({})",
+                    flow.failed(),
+                    flow.requests,
+                    kinds.join(", ")
+                ));
+            }
+            if flow.check_failures > 0 {
+                reasons.push(format!(
+                    "{} of {} requests failed checks",
+                    flow.check_failures, flow.requests
+                ));
+            }
+            let failure = (!reasons.is_empty()).then(|| reasons.join(", "));
+            let mut out = vec![format!(
+                "{:.<68} {} of {}",
+                "errors: ",
+                flow.failed(),
+                flow.requests
+            )];
+            out.extend(latency_lines(flow, &summary.percentiles));
+            let time = flow.latencies.mean() / 1_000_000.0;
+            cases.push(junit_case(
+                group,
+                &name,
+                time,
+                failure,
+                Some(&out.join("\n")),
+            ));
+            for (check, passed, failed) in &flow.checks {
+                let failure =
+                    (*failed > 0).then(|| format!("{failed} of {} failed", passed + failed));
+                let class = format!("{group}.{name}");
+                cases.push(junit_case(
+                    &class,
+                    &format!("check {check}"),
+                    0.0,
+                    failure,
+                    None,
+                ));
+            }
+        }
+        suites.push((group.to_string(), cases));
+    }
+    if !verdicts.is_empty() {
+        let cases = verdicts
+            .iter()
+            .map(|verdict| {
+                let value = verdict_value(verdict);
+                let failure = (!verdict.passed).then(|| format!("breached with {value}"));
+                junit_case("thresholds", &verdict.description, 0.0, failure, None)
+            })
+            .collect();
+        suites.push(("thresholds".into(), cases));
+    }
+
+    let count = |cases: &[(String, bool)]| {
+        (
+            cases.len(),
+            cases.iter().filter(|(_, failed)| *failed).count(),
+        )
+    };
+    let (tests, failures) = suites.iter().fold((0, 0), |(tests, failures), (_, cases)| {
+        let (suite_tests, suite_failures) = count(cases);
+        (tests + suite_tests, failures + suite_failures)
+    });
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml += &format!("<testsuites name=\"garmata\" tests=\"{tests}\" failures=\"{failures}\" time=\"{duration:.3}\">\n");
+    for (name, cases) in &suites {
+        let (tests, failures) = count(cases);
+        xml += &format!(
+            "  <testsuite name=\"{}\" tests=\"{tests}\" failures=\"{failures}\" time=\"{duration:.3}\">\n",
+            escape(name)
+        );
+        for (case, _) in cases {
+            xml += case;
+        }
+        xml += "  </testsuite>\n";
+    }
+    xml += "</testsuites>\n";
+    xml
+}
+
+/// A testcase element and whether it failed.
+fn junit_case(
+    class: &str,
+    name: &str,
+    time: f64,
+    failure: Option<String>,
+    out: Option<&str>,
+) -> (String, bool) {
+    let mut case = format!(
+        "    <testcase classname=\"{}\" name=\"{}\" time=\"{time:.3}\">\n",
+        escape(class),
+        escape(name)
+    );
+    if let Some(failure) = &failure {
+        let failure = escape(failure);
+        case += &format!("      <failure message=\"{failure}\">{failure}</failure>\n");
+    }
+    if let Some(out) = out {
+        case += &format!("      <system-out>{}</system-out>\n", escape(out));
+    }
+    case += "    </testcase>\n";
+    (case, failure.is_some())
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+const HTML_STYLE: &str = "
+body { font-family: sans-serif; margin: 2em auto; max-width: 60em; color: #222; }
+h2 { border-bottom: 1px solid #ccc; }
+section { margin-bottom: 2em; }
+table { border-collapse: collapse; margin: 0.5em 0; }
+th, td { padding: 0.2em 0.8em; text-align: right; border-bottom: 1px solid #eee; }
+th:first-child, td:first-child { text-align: left; }
+.breached { color: #c00; font-weight: bold; }
+.bar { background: #4e79a7; height: 0.8em; }
+svg { display: block; max-width: 100%; font-size: 11px; }
+svg .axis { stroke: #888; }
+pre { background: #f6f6f6; padding: 1em; overflow-x: auto; }
+";
+
+/// Colors of the lines and bars of the charts, repeated when there are more lines.
+const COLORS: [&str; 6] = [
+    "#4e79a7", "#f28e2b", "#e15759", "#76b7b2", "#59a14f", "#edc948",
+];
+
+/// One html page with inline styles and svg charts, it loads nothing over the network.
+pub fn html(report: &Report, verdicts: &[Verdict], configuration: &str) -> String {
+    let summary = &report.summary;
+    let duration = report.duration.as_secs_f64();
+    let mut html =
+        String::from("<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n");
+    html +=
+        &format!("<title>garmata report</title>\n<style>{HTML_STYLE}</style>\n</head>\n<body>\n");
+    html += &format!(
+        "<h1>garmata report</h1>\n<p>Created {} after a run of {duration:.2}s.</p>\n",
+        chrono::Local::now().format("%Y-%m-%d %H:%M:%S")
+    );
+    if summary.interval.is_none() {
+        html += "<p>The latency over time is not charted, it needs an <code>interval</code> in the configuration.</p>\n";
+    }
+    if !verdicts.is_empty() {
+        html += "<h2>Thresholds</h2>\n<table>\n<tr><th>threshold</th><th>value</th><th>result</th></tr>\n";
+        for verdict in verdicts {
+            let result = match verdict.passed {
+                true => "<td>passed</td>",
+                false => "<td class=\"breached\">BREACHED</td>",
+            };
+            html += &format!(
+                "<tr><td>{}</td><td>{}</td>{result}</tr>\n",
+                escape(&verdict.description),
+                escape(&verdict_value(verdict))
+            );
+        }
+        html += "</table>\n";
+    }
+    for group in summary.groups() {
+        let flows: Vec<_> = summary
+            .flows
+            .iter()
+            .filter(|flow| flow.group == group)
+            .collect();
+        let total = flows.iter().map(|flow| flow.requests).sum::<usize>();
+        let dropped = report
+            .dropped_iterations
+            .get(group)
+            .copied()
+            .unwrap_or_default();
+        html += &format!(
+            "<h2>Group {}</h2>\n<p>{total} requests total, {dropped} iterations dropped.</p>\n",
+            escape(group)
+        );
+        for flow in flows {
+            let name = match flow.stage.is_empty() {
+                true => flow.flow.clone(),
+                false => format!("{} (stage {})", flow.flow, flow.stage),
+            };
+            html += &format!("<section>\n<h3>Flow {}</h3>\n<table>\n", escape(&name));
+            let failed = flow.failed();
+            let mut rows = vec![
+                ("requests".to_string(), flow.requests.to_string()),
+                (
+                    "errors".into(),
+                    format!(
+                        "{failed} ({:.2}%)",
+                        failed as f64 / flow.requests as f64 * 100.0
+                    ),
+                ),
+            ];
+            for (kind, count) in &flow.errors {
+                rows.push((format!("{kind} errors"), count.to_string()));
+            }
+            rows.push(("throughput".into(), format!("{:.2} rps", flow.throughput())));
+            rows.push((
+                "received".into(),
+                format!("{:.0} bytes/s", flow.received_per_second()),
+            ));
+            for (check, passed, failed) in &flow.checks {
+                rows.push((
+                    format!("check {check}"),
+                    format!("{passed} passed, {failed} failed"),
+                ));
+            }
+            for (name, value) in rows {
+                html += &format!("<tr><td>{}</td><td>{value}</td></tr>\n", escape(&name));
+            }
+            html += "</table>\n";
+            if !flow.latencies.is_empty() {
+                html += "<h4>Latency in ms</h4>\n<table>\n<tr><th></th><th>min</th><th>avg</th>";
+                for percentile in &summary.percentiles {
+                    html += &format!("<th>p{percentile}</th>");
+                }
+                html += "<th>max</th></tr>\n";
+                html += &html_latency_row("total", &flow.latencies, &summary.percentiles);
+                for (phase, durations) in flow.phases.iter().filter(|(_, d)| d.max() > 0) {
+                    html += &html_latency_row(&phase.to_string(), durations, &summary.percentiles);
+                }
+                html += "</table>\n";
+                html += &svg_phases(flow);
+            }
+            if let Some(interval) = summary.interval {
+                html += "<h4>Latency over time</h4>\n";
+                html += &svg_time_series(flow, &summary.percentiles, interval);
+            }
+            if !flow.statuses.is_empty() {
+                html += "<h4>Status codes</h4>\n<table>\n";
+                let responses = flow.statuses.iter().map(|(_, count)| count).sum::<usize>();
+                let mut statuses = flow.statuses.clone();
+                statuses.sort();
+                for (status, count) in statuses {
+                    let share = count as f64 / responses as f64 * 100.0;
+                    html += &format!(
+                        "<tr><td>{}</td><td>{count}</td><td>{share:.2}%</td><td style=\"width: 20em\"><div class=\"bar\" style=\"width: {share:.1}%\"></div></td></tr>\n",
+                        escape(&status)
+                    );
+                }
+                html += "</table>\n";
+            }
+            html += "</section>\n";
+        }
+    }
+    html += &format!(
+        "<h2>Configuration</h2>\n<pre>{}</pre>\n</body>\n</html>\n",
+        escape(configuration)
+    );
+    html
+}
+
+fn html_latency_row(name: &str, latencies: &Histogram<u64>, percentiles: &[f64]) -> String {
+    let cell = |micros: u64| format!("<td>{:.3}</td>", micros as f64 / 1000.0);
+    let mut row = format!("<tr><td>{name}</td>{}", cell(latencies.min()));
+    row += &format!("<td>{:.3}</td>", latencies.mean() / 1000.0);
+    for percentile in percentiles {
+        row += &cell(latencies.value_at_quantile(percentile / 100.0));
+    }
+    row + &cell(latencies.max()) + "</tr>\n"
+}
+
+/// The average duration of every phase as one stacked bar.
+fn svg_phases(flow: &FlowSummary) -> String {
+    let phases: Vec<_> = flow
+        .phases
+        .iter()
+        .filter(|(_, durations)| durations.max() > 0)
+        .map(|(phase, durations)| (phase, durations.mean() / 1000.0))
+        .collect();
+    let total = phases.iter().map(|(_, avg)| avg).sum::<f64>();
+    let mut svg = String::from("<svg viewBox=\"0 0 720 60\" width=\"720\" height=\"60\">\n");
+    let mut x = 0.0;
+    for (index, (phase, avg)) in phases.iter().enumerate() {
+        let color = COLORS[index % COLORS.len()];
+        let width = avg / total * 720.0;
+        svg += &format!(
+            "<rect x=\"{x:.1}\" y=\"0\" width=\"{width:.1}\" height=\"24\" fill=\"{color}\"><title>{phase}: avg {avg:.3}ms</title></rect>\n"
+        );
+        svg += &format!(
+            "<rect x=\"{}\" y=\"38\" width=\"10\" height=\"10\" fill=\"{color}\"/><text x=\"{}\" y=\"47\">{phase} {avg:.3}ms</text>\n",
+            index * 120,
+            index * 120 + 14
+        );
+        x += width;
+    }
+    svg + "</svg>\n"
+}
+
+/// The latency percentiles of every interval as lines, intervals without responses leave gaps.
+fn svg_time_series(flow: &FlowSummary, percentiles: &[f64], interval: Duration) -> String {
+    let (left, right, top, bottom) = (60.0, 620.0, 10.0, 210.0);
+    let max = flow
+        .intervals
+        .iter()
+        .flat_map(|entry| &entry.latencies)
+        .map(|(_, latency)| *latency)
+        .max()
+        .unwrap_or_default()
+        .max(1) as f64;
+    let last = flow.intervals.len().saturating_sub(1).max(1) as f64;
+    let x = |index: usize| left + index as f64 / last * (right - left);
+    let y = |latency: u64| bottom - latency as f64 / max * (bottom - top);
+    let mut svg = String::from("<svg viewBox=\"0 0 640 260\" width=\"640\" height=\"260\">\n");
+    svg += &format!(
+        "<line class=\"axis\" x1=\"{left}\" y1=\"{top}\" x2=\"{left}\" y2=\"{bottom}\"/>\n"
+    );
+    svg += &format!(
+        "<line class=\"axis\" x1=\"{left}\" y1=\"{bottom}\" x2=\"{right}\" y2=\"{bottom}\"/>\n"
+    );
+    svg += &format!(
+        "<text x=\"{}\" y=\"{}\" text-anchor=\"end\">{:.1}ms</text>\n",
+        left - 4.0,
+        top + 4.0,
+        max / 1000.0
+    );
+    svg += &format!(
+        "<text x=\"{}\" y=\"{bottom}\" text-anchor=\"end\">0</text>\n",
+        left - 4.0
+    );
+    svg += &format!("<text x=\"{left}\" y=\"226\">0s</text>\n");
+    svg += &format!(
+        "<text x=\"{right}\" y=\"226\" text-anchor=\"end\">{}s</text>\n",
+        last as u64 * interval.as_secs()
+    );
+    for (index, percentile) in percentiles.iter().enumerate() {
+        let color = COLORS[index % COLORS.len()];
+        let mut lines = vec![vec![]];
+        for (number, entry) in flow.intervals.iter().enumerate() {
+            match entry.latencies.get(index) {
+                Some((_, latency)) => {
+                    let point = format!("{:.1},{:.1}", x(number), y(*latency));
+                    svg += &format!(
+                        "<circle cx=\"{:.1}\" cy=\"{:.1}\" r=\"2\" fill=\"{color}\"/>\n",
+                        x(number),
+                        y(*latency)
+                    );
+                    lines.last_mut().unwrap().push(point);
+                }
+                None => lines.push(vec![]),
+            }
+        }
+        for points in lines.iter().filter(|points| points.len() > 1) {
+            svg += &format!(
+                "<polyline points=\"{}\" fill=\"none\" stroke=\"{color}\" stroke-width=\"1.5\"/>\n",
+                points.join(" ")
+            );
+        }
+        svg += &format!(
+            "<rect x=\"{}\" y=\"240\" width=\"10\" height=\"10\" fill=\"{color}\"/><text x=\"{}\" y=\"249\">p{percentile}</text>\n",
+            left + index as f64 * 70.0,
+            left + index as f64 * 70.0 + 14.0
+        );
+    }
+    svg + "</svg>\n"
+}
+
+/// Count, min, avg, stddev, percentiles and max in milliseconds, null without any latency.
+fn latency_json(latencies: &Histogram<u64>, percentiles: &[f64]) -> Value {
+    if latencies.is_empty() {
+        return Value::Null;
+    }
+    let millis = |micros: u64| micros as f64 / 1000.0;
+    let mut latency = Map::new();
+    latency.insert("count".into(), json!(latencies.len()));
+    latency.insert("min".into(), json!(millis(latencies.min())));
+    latency.insert("avg".into(), json!(latencies.mean() / 1000.0));
+    latency.insert("stddev".into(), json!(latencies.stdev() / 1000.0));
+    for percentile in percentiles {
+        let value = millis(latencies.value_at_quantile(percentile / 100.0));
+        latency.insert(format!("p{percentile}"), json!(value));
+    }
+    latency.insert("max".into(), json!(millis(latencies.max())));
+    Value::Object(latency)
+}
+
+/// One row per flow and interval with the latencies in microseconds.
+pub fn time_series_csv(file: File, report: &Report) -> io::Result<()> {
+    let mut out = BufWriter::new(file);
+    let summary = &report.summary;
+    write!(
+        out,
+        "group,stage,flow,start in s,requests,errors,bytes received"
+    )?;
+    for percentile in &summary.percentiles {
+        write!(out, ",p{percentile} in μs")?;
+    }
+    writeln!(out)?;
+    for flow in &summary.flows {
+        for entry in &flow.intervals {
+            write!(
+                out,
+                "{},{},{},{},{},{},{}",
+                flow.group,
+                flow.stage,
+                flow.flow,
+                entry.start.as_secs(),
+                entry.requests,
+                entry.errors,
+                entry.received_bytes
+            )?;
+            match entry.latencies.is_empty() {
+                true => write!(out, "{}", ",".repeat(summary.percentiles.len()))?,
+                false => {
+                    for (_, latency) in &entry.latencies {
+                        write!(out, ",{latency}")?;
+                    }
+                }
+            }
+            writeln!(out)?;
+        }
+    }
+    out.flush()
+}
+
+/// Latency lines of a flow in the dotted layout of the stats.
+pub fn latency_lines(flow: &FlowSummary, percentiles: &[f64]) -> Vec<String> {
+    let latencies = &flow.latencies;
+    if latencies.is_empty() {
+        return vec![];
+    }
+    let seconds = |micros: u64| micros as f32 / 1_000_000.0;
+    let mut lines = vec![
+        format!("{:.<68} {}s", "min: ", seconds(latencies.min())),
+        format!(
+            "{:.<68} {}s",
+            "avg: ",
+            latencies.mean() as f32 / 1_000_000.0
+        ),
+        format!(
+            "{:.<68} {}s",
+            "stddev: ",
+            latencies.stdev() as f32 / 1_000_000.0
+        ),
+    ];
+    for percentile in percentiles {
+        lines.push(format!(
+            "{:.<68} {}s",
+            format!("p{percentile}: "),
+            seconds(latencies.value_at_quantile(percentile / 100.0))
+        ));
+    }
+    lines.push(format!("{:.<68} {}s", "max: ", seconds(latencies.max())));
+    // Phases that never took any time, like tls for http, are left out.
+    let millis = |micros: u64| micros as f64 / 1000.0;
+    for (phase, durations) in flow.phases.iter().filter(|(_, d)| d.max() > 0) {
+        let mut values = vec![format!("avg {:.3}ms", durations.mean() / 1000.0)];
+        for percentile in percentiles {
+            values.push(format!(
+                "p{percentile} {:.3}ms",
+                millis(durations.value_at_quantile(percentile / 100.0))
+            ));
+        }
+        values.push(format!("max {:.3}ms", millis(durations.max())));
+        lines.push(format!(
+            "{:.<68} {}",
+            format!("{phase}: "),
+            values.join(", ")
+        ));
+    }
+    lines
+}
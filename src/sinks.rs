@@ -0,0 +1,654 @@
+use crate::{configuration::Sink, http::HttpResult, GarmataError};
+use native_tls::{HandshakeError, TlsConnector};
+use serde_json::json;
+use std::{
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs, UdpSocket},
+    sync::{
+        mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use url::{Host, Url};
+
+/// Longest time a result waits for its batch to fill up.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+/// Largest UDP payload that passes common networks without fragmentation.
+const DATAGRAM_SIZE: usize = 1432;
+
+/// Receives every result of a run as soon as it arrives, for example to stream raw rows to a file.
+pub trait ResultSink: Send {
+    fn write(&mut self, result: &HttpResult) -> io::Result<()>;
+
+    /// Called once after the last result of the run.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes one csv row per result with durations in microseconds.
+/// Columns added after the first release follow the durations, so existing consumers keep their positions.
+pub struct CsvSink<W: Write + Send> {
+    out: W,
+}
+
+impl<W: Write + Send> CsvSink<W> {
+    pub fn new(mut out: W) -> io::Result<Self> {
+        writeln!(out, "start timestamp,response status,group,flow,total in μs,DNS lookup in μs,Connection in μs,TLS handshake in μs,redirecting in μs,sending in μs,waiting in μs,downloading in μs,error,failed phase,stage,check failed,connection reused,bytes received")?;
+        Ok(Self { out })
+    }
+}
+
+impl<W: Write + Send> ResultSink for CsvSink<W> {
+    fn write(&mut self, r: &HttpResult) -> io::Result<()> {
+        writeln!(
+            self.out,
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            r.start_timestamp,
+            r.response_status,
+            r.group,
+            r.flow,
+            r.total_duration.as_micros(),
+            r.dns_duration.as_micros(),
+            r.connect_duration.as_micros(),
+            r.tls_duration.as_micros(),
+            r.redirect_duration.as_micros(),
+            r.sending_duration.as_micros(),
+            r.waiting_duration.as_micros(),
+            r.download_duration.as_micros(),
+            r.error
+                .as_ref()
+                .and_then(|e| e.category())
+                .map(|category| category.to_string())
+                .unwrap_or_default(),
+            r.error
+                .as_ref()
+                .and_then(|e| e.phase())
+                .map(|phase| phase.to_string())
+                .unwrap_or_default(),
+            r.stage,
+            r.check_failed,
+            r.connection_reused,
+            r.received_bytes,
+        )
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Writes one json object per result and line with durations in microseconds.
+pub struct NdjsonSink<W: Write + Send> {
+    out: W,
+}
+
+impl<W: Write + Send> NdjsonSink<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+}
+
+impl<W: Write + Send> ResultSink for NdjsonSink<W> {
+    fn write(&mut self, r: &HttpResult) -> io::Result<()> {
+        let error = r.error.as_deref();
+        let checks: Vec<_> = r
+            .checks
+            .iter()
+            .map(|check| json!({"name": check.name, "passed": check.passed}))
+            .collect();
+        let result = json!({
+            "start_timestamp": r.start_timestamp,
+            "group": r.group,
+            "stage": r.stage,
+            "flow": r.flow,
+            "response_status": r.response_status,
+            "error": error.and_then(|e| e.category()).map(|category| category.to_string()),
+            "failed_phase": error.and_then(|e| e.phase()).map(|phase| phase.to_string()),
+            "error_message": error.map(|e| e.to_string()),
+            "check_failed": r.check_failed,
+            "checks": checks,
+            "connection_reused": r.connection_reused,
+            "received_bytes": r.received_bytes,
+            "total_us": r.total_duration.as_micros() as u64,
+            "dns_us": r.dns_duration.as_micros() as u64,
+            "connect_us": r.connect_duration.as_micros() as u64,
+            "tls_us": r.tls_duration.as_micros() as u64,
+            "redirect_us": r.redirect_duration.as_micros() as u64,
+            "sending_us": r.sending_duration.as_micros() as u64,
+            "waiting_us": r.waiting_duration.as_micros() as u64,
+            "downloading_us": r.download_duration.as_micros() as u64,
+        });
+        serde_json::to_writer(&mut self.out, &result)?;
+        writeln!(self.out)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Pushes results as Influx line protocol or StatsD metrics from a thread of its own, so a slow service never slows the test.
+pub struct PushSink {
+    format: Format,
+    prefix: String,
+    /// Open aggregates shared with the sender, which closes them once their second is over. None to push every result.
+    aggregates: Option<Arc<Mutex<Aggregates>>>,
+    /// Lines of one result or aggregate per entry.
+    lines: Option<SyncSender<String>>,
+    sender: Option<JoinHandle<()>>,
+    target: String,
+    dropped: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Influx,
+    Statsd,
+}
+
+enum Transport {
+    Http { url: Url, token: Option<String> },
+    Udp(UdpSocket),
+}
+
+/// Results per flow of the current second.
+struct Aggregates {
+    format: Format,
+    prefix: String,
+    /// Unix second of the open aggregates.
+    second: u64,
+    flows: Vec<Aggregate>,
+}
+
+/// Results of a flow within one second.
+#[derive(Default)]
+struct Aggregate {
+    group: String,
+    flow: String,
+    requests: usize,
+    errors: usize,
+    received_bytes: usize,
+    /// Requests with a response and the sum and maximum of their total durations in microseconds.
+    responses: usize,
+    total_us: u64,
+    max_us: u64,
+}
+
+impl PushSink {
+    pub fn new(sink: &Sink) -> Result<Self, GarmataError> {
+        let (format, target, transport) = match (&sink.influx_http, &sink.influx_udp, &sink.statsd)
+        {
+            (Some(url), ..) => {
+                let transport = match Url::parse(url) {
+                    Ok(url) if ["http", "https"].contains(&url.scheme()) && url.has_host() => {
+                        Transport::Http {
+                            url,
+                            token: sink.token.clone(),
+                        }
+                    }
+                    _ => {
+                        return Err(GarmataError::Configuration(format!(
+                            "influx_http needs an http or https url, got {url}"
+                        )))
+                    }
+                };
+                (Format::Influx, url, transport)
+            }
+            (_, Some(address), _) => (Format::Influx, address, udp(address)?),
+            (.., Some(address)) => (Format::Statsd, address, udp(address)?),
+            _ => unreachable!("the configuration checks that one target is set"),
+        };
+        let aggregates = sink.aggregate.then(|| {
+            Arc::new(Mutex::new(Aggregates {
+                format,
+                prefix: sink.prefix.clone(),
+                second: 0,
+                flows: vec![],
+            }))
+        });
+        let (lines, received_lines) = sync_channel(sink.buffer);
+        let batch_size = sink.batch_size;
+        let target = target.clone();
+        let sender_target = target.clone();
+        let sender_aggregates = aggregates.clone();
+        let sender = thread::spawn(move || {
+            send_batches(
+                received_lines,
+                sender_aggregates,
+                transport,
+                batch_size,
+                &sender_target,
+            )
+        });
+        Ok(Self {
+            format,
+            prefix: sink.prefix.clone(),
+            aggregates,
+            lines: Some(lines),
+            sender: Some(sender),
+            target,
+            dropped: 0,
+        })
+    }
+
+    /// Queues the lines without waiting and counts them as dropped if the buffer is full.
+    fn push(&mut self, lines: String) {
+        if let Some(sender) = &self.lines {
+            if let Err(TrySendError::Full(_)) = sender.try_send(lines) {
+                self.dropped += 1;
+            }
+        }
+    }
+}
+
+impl Aggregates {
+    /// Counts the result into the current second and returns the lines of a second that is over.
+    fn record(&mut self, r: &HttpResult) -> Vec<String> {
+        let closed = self.close(false);
+        let index = match self
+            .flows
+            .iter()
+            .position(|flow| flow.group == r.group && flow.flow == r.flow)
+        {
+            Some(index) => index,
+            None => {
+                self.flows.push(Aggregate {
+                    group: r.group.clone(),
+                    flow: r.flow.clone(),
+                    ..Default::default()
+                });
+                self.flows.len() - 1
+            }
+        };
+        let flow = &mut self.flows[index];
+        flow.requests += 1;
+        flow.received_bytes += r.received_bytes;
+        if r.is_error() {
+            flow.errors += 1;
+        }
+        if r.error.is_none() {
+            let total = r.total_duration.as_micros() as u64;
+            flow.responses += 1;
+            flow.total_us += total;
+            flow.max_us = flow.max_us.max(total);
+        }
+        closed
+    }
+
+    /// Lines of the open aggregates once their second is over, or right away at the end of the run.
+    fn close(&mut self, end: bool) -> Vec<String> {
+        let now = unix_time().as_secs();
+        if now == self.second && !end {
+            return vec![];
+        }
+        let second = std::mem::replace(&mut self.second, now);
+        let flows = std::mem::take(&mut self.flows);
+        let mut all = vec![];
+        for flow in flows {
+            let lines = match self.format {
+                Format::Influx => {
+                    let mut fields = format!(
+                        "requests={}i,errors={}i,received_bytes={}i",
+                        flow.requests, flow.errors, flow.received_bytes
+                    );
+                    if flow.responses > 0 {
+                        fields += &format!(
+                            ",avg_us={},max_us={}i",
+                            flow.total_us as f64 / flow.responses as f64,
+                            flow.max_us
+                        );
+                    }
+                    format!(
+                        "{}{} {fields} {}",
+                        influx_key(&format!("{}_per_second", self.prefix)),
+                        influx_tags(&[("group", &flow.group), ("flow", &flow.flow)]),
+                        second * 1_000_000_000
+                    )
+                }
+                Format::Statsd => {
+                    let name = statsd_name(&self.prefix, &flow.group, &flow.flow);
+                    let mut lines = vec![
+                        format!("{name}.requests:{}|c", flow.requests),
+                        format!("{name}.errors:{}|c", flow.errors),
+                        format!("{name}.received_bytes:{}|c", flow.received_bytes),
+                    ];
+                    if flow.responses > 0 {
+                        let avg = flow.total_us as f64 / flow.responses as f64 / 1000.0;
+                        lines.push(format!("{name}.total_avg:{avg:.3}|g"));
+                        lines.push(format!(
+                            "{name}.total_max:{:.3}|g",
+                            flow.max_us as f64 / 1000.0
+                        ));
+                    }
+                    lines.join("\n")
+                }
+            };
+            all.push(lines);
+        }
+        all
+    }
+}
+
+impl ResultSink for PushSink {
+    fn write(&mut self, r: &HttpResult) -> io::Result<()> {
+        if let Some(aggregates) = &self.aggregates {
+            let closed = aggregates.lock().unwrap().record(r);
+            for lines in closed {
+                self.push(lines);
+            }
+            return Ok(());
+        }
+        let lines = match self.format {
+            Format::Influx => {
+                let status = match r.response_status.is_empty() {
+                    true => "none",
+                    false => &r.response_status,
+                };
+                let error = r
+                    .error
+                    .as_ref()
+                    .and_then(|e| e.category())
+                    .map(|category| category.to_string())
+                    .unwrap_or_default();
+                let tags = influx_tags(&[
+                    ("group", &r.group),
+                    ("flow", &r.flow),
+                    ("stage", &r.stage),
+                    ("status", status),
+                    ("error", &error),
+                ]);
+                let timestamp = chrono::DateTime::parse_from_rfc3339(&r.start_timestamp)
+                    .ok()
+                    .and_then(|timestamp| timestamp.timestamp_nanos_opt())
+                    .map(|nanos| format!(" {nanos}"))
+                    .unwrap_or_default();
+                format!(
+                    "{}{tags} total_us={}i,dns_us={}i,connect_us={}i,tls_us={}i,redirect_us={}i,sending_us={}i,waiting_us={}i,downloading_us={}i,received_bytes={}i,check_failed={},connection_reused={}{timestamp}",
+                    influx_key(&self.prefix),
+                    r.total_duration.as_micros(),
+                    r.dns_duration.as_micros(),
+                    r.connect_duration.as_micros(),
+                    r.tls_duration.as_micros(),
+                    r.redirect_duration.as_micros(),
+                    r.sending_duration.as_micros(),
+                    r.waiting_duration.as_micros(),
+                    r.download_duration.as_micros(),
+                    r.received_bytes,
+                    r.check_failed,
+                    r.connection_reused,
+                )
+            }
+            Format::Statsd => {
+                let name = statsd_name(&self.prefix, &r.group, &r.flow);
+                let mut lines = vec![
+                    format!("{name}.requests:1|c"),
+                    format!("{name}.received_bytes:{}|c", r.received_bytes),
+                ];
+                if r.is_error() {
+                    lines.push(format!("{name}.errors:1|c"));
+                }
+                if r.error.is_none() {
+                    let phases = r
+                        .phase_durations()
+                        .map(|(phase, duration)| (phase.name(), duration));
+                    for (phase, duration) in [("total", r.total_duration)].into_iter().chain(phases)
+                    {
+                        let millis = duration.as_secs_f64() * 1000.0;
+                        lines.push(format!("{name}.{phase}:{millis:.3}|ms"));
+                    }
+                }
+                lines.join("\n")
+            }
+        };
+        self.push(lines);
+        Ok(())
+    }
+
+    /// Pushes the open aggregates and waits until everything in the buffer is sent.
+    fn finish(&mut self) -> io::Result<()> {
+        if let Some(aggregates) = self.aggregates.take() {
+            let closed = aggregates.lock().unwrap().close(true);
+            for lines in closed {
+                self.push(lines);
+            }
+        }
+        // The sender sends the rest and stops once the buffer is closed.
+        drop(self.lines.take());
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.join();
+        }
+        if self.dropped > 0 {
+            eprintln!(
+                "{} results were not pushed to {} because the buffer was full",
+                self.dropped, self.target
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Time since the unix epoch.
+fn unix_time() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// A socket connected to the address, bound to an address of the same family.
+fn udp(address: &str) -> Result<Transport, GarmataError> {
+    let connect = || {
+        let target = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address found"))?;
+        let local = match target.is_ipv4() {
+            true => "0.0.0.0:0",
+            false => "[::]:0",
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(target)?;
+        Ok::<_, io::Error>(socket)
+    };
+    connect()
+        .map(Transport::Udp)
+        .map_err(|e| GarmataError::Configuration(format!("cannot push results to {address}: {e}")))
+}
+
+/// Sends the lines once a batch is full or its first line waited for the flush interval.
+/// Only the first of consecutive failures is reported.
+fn send_batches(
+    lines: Receiver<String>,
+    aggregates: Option<Arc<Mutex<Aggregates>>>,
+    transport: Transport,
+    batch_size: usize,
+    target: &str,
+) {
+    let mut batch: Vec<String> = vec![];
+    let mut first = Instant::now();
+    let mut failing = false;
+    loop {
+        let mut timeout = FLUSH_INTERVAL.saturating_sub(first.elapsed());
+        if aggregates.is_some() {
+            // Wakes up when the second ends to close its aggregates, even without further results.
+            let next_second =
+                Duration::from_secs(1) - Duration::from_nanos(unix_time().subsec_nanos().into());
+            timeout = timeout.min(next_second);
+        }
+        let open = match lines.recv_timeout(timeout) {
+            Ok(line) => {
+                if batch.is_empty() {
+                    first = Instant::now();
+                }
+                batch.push(line);
+                true
+            }
+            Err(RecvTimeoutError::Timeout) => true,
+            Err(RecvTimeoutError::Disconnected) => false,
+        };
+        if let Some(aggregates) = &aggregates {
+            let closed = aggregates.lock().unwrap().close(false);
+            if batch.is_empty() && !closed.is_empty() {
+                first = Instant::now();
+            }
+            batch.extend(closed);
+        }
+        let due = batch.len() >= batch_size || first.elapsed() >= FLUSH_INTERVAL || !open;
+        if due && !batch.is_empty() {
+            match transport.send(&batch) {
+                Ok(()) => failing = false,
+                Err(e) if !failing => {
+                    eprintln!("cannot push results to {target}: {e}");
+                    failing = true;
+                }
+                Err(_) => {}
+            }
+            batch.clear();
+        }
+        if batch.is_empty() {
+            first = Instant::now();
+        }
+        if !open {
+            return;
+        }
+    }
+}
+
+impl Transport {
+    fn send(&self, batch: &[String]) -> io::Result<()> {
+        match self {
+            Self::Udp(socket) => {
+                let mut datagram = String::new();
+                for line in batch.iter().flat_map(|lines| lines.split('\n')) {
+                    if !datagram.is_empty() && datagram.len() + 1 + line.len() > DATAGRAM_SIZE {
+                        socket.send(datagram.as_bytes())?;
+                        datagram.clear();
+                    }
+                    if !datagram.is_empty() {
+                        datagram.push('\n');
+                    }
+                    datagram += line;
+                }
+                socket.send(datagram.as_bytes())?;
+                Ok(())
+            }
+            Self::Http { url, token } => {
+                let host = url.host_str().unwrap_or_default();
+                let body = batch.join("\n");
+                let mut request = format!(
+                    "POST {}{} HTTP/1.1\r\nHost: {host}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n",
+                    url.path(),
+                    url.query().map(|query| format!("?{query}")).unwrap_or_default(),
+                    body.len()
+                );
+                if let Some(token) = token {
+                    request += &format!("Authorization: Token {token}\r\n");
+                }
+                request += "\r\n";
+                request += &body;
+                // Resolves host names and strips the brackets of ipv6 addresses.
+                let socket = TcpStream::connect(&*url.socket_addrs(|| None)?)?;
+                socket.set_read_timeout(Some(Duration::from_secs(10)))?;
+                socket.set_write_timeout(Some(Duration::from_secs(10)))?;
+                let mut response = vec![];
+                match url.scheme() {
+                    "https" => {
+                        let connector = TlsConnector::new().map_err(io::Error::other)?;
+                        // Blocking sockets only interrupt the handshake when their timeout elapsed.
+                        let domain = match url.host() {
+                            Some(Host::Ipv6(address)) => address.to_string(),
+                            _ => host.to_string(),
+                        };
+                        let mut stream =
+                            connector.connect(&domain, socket).map_err(|e| match e {
+                                HandshakeError::Failure(e) => io::Error::other(e),
+                                HandshakeError::WouldBlock(_) => io::ErrorKind::TimedOut.into(),
+                            })?;
+                        stream.write_all(request.as_bytes())?;
+                        stream.read_to_end(&mut response)?;
+                    }
+                    _ => {
+                        let mut stream = socket;
+                        stream.write_all(request.as_bytes())?;
+                        stream.read_to_end(&mut response)?;
+                    }
+                }
+                let response = String::from_utf8_lossy(&response);
+                let status = response.lines().next().unwrap_or_default();
+                match status.split(' ').nth(1) {
+                    Some(code) if code.starts_with('2') => Ok(()),
+                    _ => Err(io::Error::other(format!("unexpected response {status}"))),
+                }
+            }
+        }
+    }
+}
+
+/// Tags with a value, escaped for the line protocol. Empty values are not allowed.
+fn influx_tags(tags: &[(&str, &str)]) -> String {
+    tags.iter()
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(key, value)| format!(",{key}={}", influx_key(value)))
+        .collect()
+}
+
+fn influx_key(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+        .replace('\n', "\\n")
+}
+
+/// Dot separated metric name with every part reduced to characters StatsD servers accept.
+fn statsd_name(prefix: &str, group: &str, flow: &str) -> String {
+    [prefix, group, flow]
+        .iter()
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            part.chars()
+                .map(
+                    |c| match c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                        true => c,
+                        false => '_',
+                    },
+                )
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn csv_keeps_the_original_columns_first() {
+        let mut sink = CsvSink::new(vec![]).unwrap();
+        let result = HttpResult {
+            group: "g".into(),
+            flow: "f".into(),
+            stage: "s".into(),
+            start_timestamp: "2023-06-01T10:00:00.000Z".into(),
+            response_status: "200".into(),
+            total_duration: Duration::from_micros(900),
+            waiting_duration: Duration::from_micros(700),
+            received_bytes: 42,
+            ..Default::default()
+        };
+        sink.write(&result).unwrap();
+        let csv = String::from_utf8(sink.out).unwrap();
+        let lines: Vec<_> = csv.lines().collect();
+        assert!(lines[0].starts_with("start timestamp,response status,group,flow,total in μs,DNS lookup in μs,Connection in μs,TLS handshake in μs,redirecting in μs,sending in μs,waiting in μs,downloading in μs,"));
+        assert_eq!(lines[0].split(',').count(), lines[1].split(',').count());
+        assert_eq!(
+            lines[1],
+            "2023-06-01T10:00:00.000Z,200,g,f,900,0,0,0,0,0,700,0,,,s,false,false,42"
+        );
+    }
+}
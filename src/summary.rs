@@ -0,0 +1,331 @@
+use crate::{error::Phase, http::HttpResult};
+use hdrhistogram::Histogram;
+use std::time::Duration;
+
+/// Statistics of all results of a run, updated as the results arrive instead of keeping them.
+#[derive(Debug, Clone, Default)]
+pub struct Summary {
+    /// Flows in the order their first result arrived.
+    pub flows: Vec<FlowSummary>,
+    /// Length of the intervals of the time series, none to collect no time series.
+    pub interval: Option<Duration>,
+    /// Latency percentiles kept for every interval.
+    pub percentiles: Vec<f64>,
+}
+
+/// Counters and latency histogram of one flow of a group within one stage.
+#[derive(Debug, Clone)]
+pub struct FlowSummary {
+    pub group: String,
+    pub flow: String,
+    pub stage: String,
+    pub requests: usize,
+    /// Failed requests per error category or `status` for responses with an error status.
+    pub errors: Vec<(String, usize)>,
+    /// Requests with at least one failed check.
+    pub check_failures: usize,
+    /// Name, passed and failed count of each check.
+    pub checks: Vec<(String, usize, usize)>,
+    /// Responses per status code, requests without a response are not counted.
+    pub statuses: Vec<(String, usize)>,
+    /// Total durations in microseconds of the requests that received a response.
+    /// Values are kept with three significant digits.
+    pub latencies: Histogram<u64>,
+    /// Durations in microseconds of each phase of the requests that received a response.
+    pub phases: Vec<(Phase, Histogram<u64>)>,
+    pub received_bytes: usize,
+    /// Start of the first and end of the last request since the start of the run.
+    pub first_start: Duration,
+    pub last_end: Duration,
+    /// Time series of the flow, one entry per interval since the start of the run.
+    pub intervals: Vec<Interval>,
+    /// Latencies of the last interval until it is closed.
+    interval_latencies: Histogram<u64>,
+}
+
+/// Results of a flow that finished within one interval of the run.
+#[derive(Debug, Clone, Default)]
+pub struct Interval {
+    /// Start of the interval since the start of the run.
+    pub start: Duration,
+    pub requests: usize,
+    /// Requests that failed or received an error status.
+    pub errors: usize,
+    pub received_bytes: usize,
+    /// Percentile and total duration in microseconds, empty if no request received a response.
+    pub latencies: Vec<(f64, u64)>,
+}
+
+impl Summary {
+    pub fn new(interval: Option<Duration>, percentiles: Vec<f64>) -> Self {
+        Self {
+            flows: vec![],
+            interval,
+            percentiles,
+        }
+    }
+
+    /// Records a result that finished the given time after the start of the run.
+    pub fn record(&mut self, result: &HttpResult, elapsed: Duration) {
+        let index = match self.flows.iter().position(|flow| {
+            flow.group == result.group && flow.flow == result.flow && flow.stage == result.stage
+        }) {
+            Some(index) => index,
+            None => {
+                self.flows
+                    .push(FlowSummary::new(&result.group, &result.flow, &result.stage));
+                self.flows.len() - 1
+            }
+        };
+        self.flows[index].record(result, elapsed);
+        if let Some(interval) = self.interval {
+            let number = (elapsed.as_nanos() / interval.as_nanos()) as usize;
+            self.flows[index].record_interval(result, number, interval, &self.percentiles);
+        }
+    }
+
+    /// Closes the last interval of every flow once all results are recorded.
+    pub fn finish(&mut self) {
+        for flow in &mut self.flows {
+            flow.close_interval(&self.percentiles);
+        }
+    }
+
+    /// All flows of the given group and flow name merged into one, none if no result matched.
+    /// The time series are not merged.
+    pub fn merged(&self, group: Option<&str>, flow: Option<&str>) -> Option<FlowSummary> {
+        let mut matching = self
+            .flows
+            .iter()
+            .filter(|summary| group.is_none_or(|group| group == summary.group))
+            .filter(|summary| flow.is_none_or(|flow| flow == summary.flow));
+        let mut merged = matching.next()?.clone();
+        for summary in matching {
+            merged.merge(summary);
+        }
+        Some(merged)
+    }
+
+    /// Group names in the order their first result arrived.
+    pub fn groups(&self) -> Vec<&str> {
+        let mut groups: Vec<&str> = vec![];
+        for flow in &self.flows {
+            if !groups.contains(&flow.group.as_str()) {
+                groups.push(&flow.group);
+            }
+        }
+        groups
+    }
+}
+
+impl FlowSummary {
+    fn new(group: &str, flow: &str, stage: &str) -> Self {
+        Self {
+            group: group.to_string(),
+            flow: flow.to_string(),
+            stage: stage.to_string(),
+            requests: 0,
+            errors: vec![],
+            check_failures: 0,
+            checks: vec![],
+            statuses: vec![],
+            latencies: Histogram::new(3).unwrap(),
+            phases: [
+                Phase::Dns,
+                Phase::Connect,
+                Phase::Tls,
+                Phase::Sending,
+                Phase::Waiting,
+                Phase::Downloading,
+            ]
+            .into_iter()
+            .map(|phase| (phase, Histogram::new(3).unwrap()))
+            .collect(),
+            received_bytes: 0,
+            first_start: Duration::MAX,
+            last_end: Duration::ZERO,
+            intervals: vec![],
+            interval_latencies: Histogram::new(3).unwrap(),
+        }
+    }
+
+    fn record(&mut self, result: &HttpResult, elapsed: Duration) {
+        self.requests += 1;
+        self.first_start = self
+            .first_start
+            .min(elapsed.saturating_sub(result.total_duration));
+        self.last_end = self.last_end.max(elapsed);
+        self.received_bytes += result.received_bytes;
+        if let Some(kind) = result.error_kind() {
+            count(&mut self.errors, kind, 1);
+        }
+        if !result.response_status.is_empty() {
+            count(&mut self.statuses, result.response_status.clone(), 1);
+        }
+        if result.check_failed {
+            self.check_failures += 1;
+        }
+        for check in &result.checks {
+            let (passed, failed) = match check.passed {
+                true => (1, 0),
+                false => (0, 1),
+            };
+            count_check(&mut self.checks, &check.name, passed, failed);
+        }
+        // The histograms grow with the values, so recording cannot fail.
+        if result.error.is_none() {
+            let _ = self
+                .latencies
+                .record(result.total_duration.as_micros() as u64);
+            for (phase, duration) in result.phase_durations() {
+                if let Some((_, histogram)) =
+                    self.phases.iter_mut().find(|(other, _)| *other == phase)
+                {
+                    let _ = histogram.record(duration.as_micros() as u64);
+                }
+            }
+        }
+    }
+
+    /// Results arrive in the order they finish, so only the last interval is open.
+    fn record_interval(
+        &mut self,
+        result: &HttpResult,
+        number: usize,
+        length: Duration,
+        percentiles: &[f64],
+    ) {
+        if self.intervals.len() <= number {
+            self.close_interval(percentiles);
+            // Intervals without any result of the flow stay in the time series.
+            while self.intervals.len() <= number {
+                self.intervals.push(Interval {
+                    start: length * self.intervals.len() as u32,
+                    ..Default::default()
+                });
+            }
+        }
+        let interval = self.intervals.last_mut().unwrap();
+        interval.requests += 1;
+        interval.received_bytes += result.received_bytes;
+        if result.is_error() {
+            interval.errors += 1;
+        }
+        if result.error.is_none() {
+            let _ = self
+                .interval_latencies
+                .record(result.total_duration.as_micros() as u64);
+        }
+    }
+
+    /// Reduces the latencies of the last interval to its percentiles.
+    fn close_interval(&mut self, percentiles: &[f64]) {
+        let latencies = &mut self.interval_latencies;
+        if let (Some(interval), false) = (self.intervals.last_mut(), latencies.is_empty()) {
+            interval.latencies = percentiles
+                .iter()
+                .map(|&percentile| (percentile, latencies.value_at_quantile(percentile / 100.0)))
+                .collect();
+            latencies.reset();
+        }
+    }
+
+    fn merge(&mut self, other: &FlowSummary) {
+        self.requests += other.requests;
+        self.received_bytes += other.received_bytes;
+        self.first_start = self.first_start.min(other.first_start);
+        self.last_end = self.last_end.max(other.last_end);
+        for (kind, errors) in &other.errors {
+            count(&mut self.errors, kind.clone(), *errors);
+        }
+        self.check_failures += other.check_failures;
+        for (name, passed, failed) in &other.checks {
+            count_check(&mut self.checks, name, *passed, *failed);
+        }
+        for (status, responses) in &other.statuses {
+            count(&mut self.statuses, status.clone(), *responses);
+        }
+        // All histograms resize automatically, so adding cannot fail.
+        let _ = self.latencies.add(&other.latencies);
+        for ((_, histogram), (_, other)) in self.phases.iter_mut().zip(&other.phases) {
+            let _ = histogram.add(other);
+        }
+    }
+
+    /// Requests that failed or received an error status.
+    pub fn failed(&self) -> usize {
+        self.errors.iter().map(|(_, count)| count).sum()
+    }
+
+    /// Time the flow ran, from the start of its first to the end of its last request.
+    /// Flows of a short group or stage are not diluted by the rest of the run.
+    pub fn duration(&self) -> Duration {
+        self.last_end.saturating_sub(self.first_start)
+    }
+
+    /// Requests per second while the flow ran.
+    pub fn throughput(&self) -> f64 {
+        self.per_second(self.requests)
+    }
+
+    /// Received bytes per second while the flow ran.
+    pub fn received_per_second(&self) -> f64 {
+        self.per_second(self.received_bytes)
+    }
+
+    fn per_second(&self, count: usize) -> f64 {
+        match self.duration().is_zero() {
+            true => 0.0,
+            false => count as f64 / self.duration().as_secs_f64(),
+        }
+    }
+}
+
+fn count(counts: &mut Vec<(String, usize)>, kind: String, amount: usize) {
+    match counts.iter_mut().find(|(other, _)| *other == kind) {
+        Some((_, count)) => *count += amount,
+        None => counts.push((kind, amount)),
+    }
+}
+
+fn count_check(checks: &mut Vec<(String, usize, usize)>, name: &str, passed: usize, failed: usize) {
+    match checks.iter_mut().find(|(other, ..)| other == name) {
+        Some(check) => {
+            check.1 += passed;
+            check.2 += failed;
+        }
+        None => checks.push((name.to_string(), passed, failed)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(group: &str, total: u64) -> HttpResult {
+        HttpResult {
+            group: group.into(),
+            flow: "flow".into(),
+            response_status: "200".into(),
+            total_duration: Duration::from_secs(total),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn throughput_over_the_time_the_flow_ran() {
+        let mut summary = Summary::new(None, vec![]);
+        summary.record(&result("short", 1), Duration::from_secs(2));
+        for end in 3..=10 {
+            summary.record(&result("long", 1), Duration::from_secs(end));
+        }
+        let short = summary.merged(Some("short"), None).unwrap();
+        assert_eq!(short.duration(), Duration::from_secs(1));
+        assert_eq!(short.throughput(), 1.0);
+        let long = summary.merged(Some("long"), None).unwrap();
+        assert_eq!(long.duration(), Duration::from_secs(8));
+        assert_eq!(long.throughput(), 1.0);
+        let all = summary.merged(None, None).unwrap();
+        assert_eq!(all.duration(), Duration::from_secs(9));
+    }
+}
@@ -0,0 +1,80 @@
+use crate::{
+    configuration::{Metric, Threshold},
+    summary::FlowSummary,
+    Report,
+};
+pub struct Verdict {
+    pub description: String,
+    /// Measured value or none if no result matched the group and flow of the threshold.
+    pub value: Option<f64>,
+    pub unit: &'static str,
+    pub passed: bool,
+}
+
+pub fn evaluate(thresholds: &[Threshold], report: &Report) -> Vec<Verdict> {
+    thresholds
+        .iter()
+        .map(|threshold| {
+            let value = report
+                .summary
+                .merged(threshold.group.as_deref(), threshold.flow.as_deref())
+                .and_then(|summary| value(threshold.metric, &summary));
+            let passed = value.is_some_and(|value| {
+                threshold.below.is_none_or(|below| value < below)
+                    && threshold.above.is_none_or(|above| value > above)
+            });
+            Verdict {
+                description: describe(threshold),
+                value,
+                unit: unit(threshold.metric),
+                passed,
+            }
+        })
+        .collect()
+}
+
+fn value(metric: Metric, summary: &FlowSummary) -> Option<f64> {
+    let count = summary.requests as f64;
+    let rate = |matches: usize| matches as f64 / count * 100.0;
+    // Failed requests have no response and are not part of the latencies.
+    let latencies = &summary.latencies;
+    let millis = |micros: u64| micros as f64 / 1000.0;
+    match metric {
+        Metric::ErrorRate => Some(rate(summary.failed())),
+        Metric::CheckFailureRate => Some(rate(summary.check_failures)),
+        Metric::Throughput => Some(summary.throughput()),
+        _ if latencies.is_empty() => None,
+        Metric::Min => Some(millis(latencies.min())),
+        Metric::Max => Some(millis(latencies.max())),
+        Metric::Avg => Some(latencies.mean() / 1000.0),
+        Metric::Percentile(percentile) => {
+            Some(millis(latencies.value_at_quantile(percentile / 100.0)))
+        }
+    }
+}
+
+fn unit(metric: Metric) -> &'static str {
+    match metric {
+        Metric::ErrorRate | Metric::CheckFailureRate => "%",
+        Metric::Throughput => " rps",
+        _ => "ms",
+    }
+}
+
+fn describe(threshold: &Threshold) -> String {
+    let mut description = threshold.metric.to_string();
+    match (&threshold.group, &threshold.flow) {
+        (Some(group), Some(flow)) => description += &format!(" of flow {flow} in group {group}"),
+        (Some(group), None) => description += &format!(" of group {group}"),
+        (None, Some(flow)) => description += &format!(" of flow {flow}"),
+        (None, None) => {}
+    }
+    let unit = unit(threshold.metric);
+    if let Some(below) = threshold.below {
+        description += &format!(" below {below}{unit}");
+    }
+    if let Some(above) = threshold.above {
+        description += &format!(" above {above}{unit}");
+    }
+    description
+}